@@ -0,0 +1,283 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Cursors on the databases of a mutable transaction. A cursor keeps
+// the path from the root to its current binding, and moves along it
+// in both directions. Deletions and replacements go through the usual
+// copy-on-write functions, which record the pages and positions they
+// wrote on the way back up (see `txn::MutTxn::path`), and the cursor
+// rebuilds its path from these. When they don't, for instance because
+// pages were merged or split, the cursor seeks its position again
+// from the root.
+
+use super::{MutTxn, Db, Error, Value};
+use super::txn::{self, LoadPage, P, UnsafeValue, FIRST_HEAD, NIL};
+use rand::Rng;
+
+/// A cursor on a database of a mutable transaction, created with
+/// `MutTxn::cursor`. Unlike `Iter`, a cursor can delete or replace
+/// the binding it is on, and keep going from there.
+///
+/// A new cursor is not positioned on any binding: `next` moves it to
+/// the first binding, and `prev` to the last one. Moving past either
+/// end unpositions it again.
+pub struct Cursor<'c, 'env:'c, T:'c> {
+    txn: &'c mut MutTxn<'env, T>,
+    db: &'c mut Db,
+    // Path from the root to the current binding, as `page | offset`.
+    stack: [u64;52],
+    pointer: usize,
+    // Path recorded by the last change, kept allocated.
+    path: Vec<u64>,
+}
+
+impl<'c, 'env, T> Cursor<'c, 'env, T> {
+    #[doc(hidden)]
+    pub fn new(txn: &'c mut MutTxn<'env, T>, db: &'c mut Db) -> Cursor<'c, 'env, T> {
+        Cursor {
+            txn: txn,
+            db: db,
+            stack: [0;52],
+            pointer: 0,
+            path: Vec::new(),
+        }
+    }
+
+    /// Move to the first binding greater than or equal to `(key,
+    /// value)` (or to the first binding of `key`, if `value` is
+    /// `None`), and return it. Complexity O(log |```db```|).
+    pub fn seek(&mut self, key: &[u8], value: Option<&[u8]>) -> Option<(&[u8], Value<'_, MutTxn<'env, T>>)> {
+        unsafe {
+            let value = value.map(|x| UnsafeValue::S { p:x.as_ptr(), len:x.len() as u32 });
+            self.seek_(key, value);
+        }
+        self.current()
+    }
+
+    /// The binding the cursor is on, if any.
+    pub fn current(&self) -> Option<(&[u8], Value<'_, MutTxn<'env, T>>)> {
+        if self.pointer == 0 {
+            return None
+        }
+        unsafe {
+            let (page_off, off) = txn::offsets(self.stack[self.pointer - 1]);
+            let page = self.txn.load_page(page_off);
+            let (key, value) = txn::read_key_value(page.offset(off as isize));
+            Some((key, Value::from_unsafe(&value, &*self.txn)))
+        }
+    }
+
+    /// Move to the next binding, and return it. A cursor is not an
+    /// `Iterator`, since its bindings borrow it.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(&[u8], Value<'_, MutTxn<'env, T>>)> {
+        unsafe {
+            if self.pointer == 0 {
+                self.stack[0] = self.db.root | (FIRST_HEAD as u64);
+                self.pointer = 1
+            }
+            self.step_next();
+        }
+        self.current()
+    }
+
+    /// Move to the previous binding, and return it.
+    pub fn prev(&mut self) -> Option<(&[u8], Value<'_, MutTxn<'env, T>>)> {
+        unsafe {
+            if self.pointer == 0 {
+                let root = self.db.root;
+                self.descend_last(root)
+            } else {
+                self.step_prev()
+            }
+        }
+        self.current()
+    }
+
+    /// Delete the binding the cursor is on, and move to the next
+    /// one. Returns `false` if the cursor was not on a binding.
+    /// Atomic, like `MutTxn::del`.
+    ///
+    /// Complexity O(log |```db```|). The path of the cursor is
+    /// rebuilt from the pages written by `MutTxn::del`, except if the
+    /// binding was not in a leaf, or if pages were merged, in which
+    /// case the cursor descends the tree again to find its position.
+    pub fn delete_current<R:Rng>(&mut self, rng: &mut R) -> Result<bool, Error> {
+        let (key, value) = match self.current_owned() {
+            Some(x) => x,
+            None => return Ok(false)
+        };
+        self.record();
+        let result = self.txn.del(rng, self.db, &key, Some(&value));
+        unsafe {
+            if self.repair(result.is_ok()) {
+                // The path ends just before the deleted binding.
+                self.step_next()
+            } else {
+                // If the deletion failed, this moves back to the same
+                // binding.
+                self.seek_(&key, Some(UnsafeValue::S { p:value.as_ptr(), len:value.len() as u32 }))
+            }
+        }
+        result.map(|_| true)
+    }
+
+    /// Same as `delete_current`, using the generator of the transaction.
+    pub fn delete_current_auto(&mut self) -> Result<bool, Error> {
+        let mut rng = self.txn.rng.clone();
+        let result = self.delete_current(&mut rng);
+        self.txn.rng = rng;
+        result
+    }
+
+    /// Replace the value of the binding the cursor is on, and stay on
+    /// the new binding. Returns `false` if the cursor was not on a
+    /// binding. Atomic, like `MutTxn::replace`.
+    ///
+    /// Complexity O(log |```db```|). If the cursor is not on the
+    /// smallest binding of its key, which `replace` would change
+    /// instead, this is `del` followed by `put`. As in
+    /// `delete_current`, the path of the cursor is rebuilt from the
+    /// pages written by the last of these, unless a page was split,
+    /// in which case the cursor descends the tree again.
+    pub fn replace_current<R:Rng>(&mut self, rng: &mut R, value: &[u8]) -> Result<bool, Error> {
+        let (key, old_value) = match self.current_owned() {
+            Some(x) => x,
+            None => return Ok(false)
+        };
+        let first = self.db.is_unique() || self.first_of_key(&key);
+        self.record();
+        let result = if first {
+            self.txn.replace(rng, self.db, &key, value)
+        } else {
+            self.txn.atomically(self.db, |txn, db| {
+                try!(txn.del(rng, db, &key, Some(&old_value)));
+                txn.put(rng, db, &key, value).map(|_| ())
+            })
+        };
+        if !self.repair(result.is_ok()) {
+            let value = if result.is_ok() { value } else { &old_value[..] };
+            unsafe {
+                self.seek_(&key, Some(UnsafeValue::S { p:value.as_ptr(), len:value.len() as u32 }))
+            }
+        }
+        result.map(|_| true)
+    }
+
+    /// Same as `replace_current`, using the generator of the transaction.
+    pub fn replace_current_auto(&mut self, value: &[u8]) -> Result<bool, Error> {
+        let mut rng = self.txn.rng.clone();
+        let result = self.replace_current(&mut rng, value);
+        self.txn.rng = rng;
+        result
+    }
+
+    // Whether the binding the cursor is on is the first one of `key`.
+    fn first_of_key(&mut self, key: &[u8]) -> bool {
+        let (stack, pointer) = (self.stack, self.pointer);
+        unsafe { self.step_prev() }
+        let first = self.current().map(|(k, _)| k != key).unwrap_or(true);
+        self.stack = stack;
+        self.pointer = pointer;
+        first
+    }
+
+    // Ask the next change to record its path.
+    fn record(&mut self) {
+        let mut path = std::mem::take(&mut self.path);
+        path.clear();
+        self.txn.path = Some(path)
+    }
+
+    // Take back the path recorded by the last change, and rebuild the
+    // stack from it if the change succeeded. Returns whether it did.
+    fn repair(&mut self, ok: bool) -> bool {
+        self.path = self.txn.path.take().unwrap_or_default();
+        if !ok || self.path.is_empty() {
+            return false
+        }
+        for (i, &x) in self.path.iter().rev().enumerate() {
+            self.stack[i] = x
+        }
+        self.pointer = self.path.len();
+        debug_assert_eq!(txn::offsets(self.stack[0]).0, self.db.root);
+        true
+    }
+
+    fn current_owned(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.current().map(|(key, value)| {
+            (key.to_vec(),
+             value.fold(Vec::new(), |mut v, x| { v.extend_from_slice(x); v }))
+        })
+    }
+
+    unsafe fn seek_(&mut self, key: &[u8], value: Option<UnsafeValue>) {
+        self.pointer = txn::seek_path(&*self.txn, self.db.root, key, value, false, &mut self.stack);
+        self.step_next();
+    }
+
+    // Move to the binding after the top of the stack, emptying the
+    // stack if there is none.
+    unsafe fn step_next(&mut self) {
+        // Everything in the page below the current binding comes next.
+        {
+            let (page_off, off) = txn::offsets(self.stack[self.pointer - 1]);
+            let mut child = txn::right_child(&self.txn.load_page(page_off), off);
+            while child != 0 {
+                self.stack[self.pointer] = child | (FIRST_HEAD as u64);
+                self.pointer += 1;
+                child = txn::right_child(&self.txn.load_page(child), FIRST_HEAD);
+            }
+        }
+        while self.pointer > 0 {
+            let (page_off, off) = txn::offsets(self.stack[self.pointer - 1]);
+            let next = txn::next_at_level(&self.txn.load_page(page_off), off, 0);
+            if next != NIL {
+                self.stack[self.pointer - 1] = page_off | (next as u64);
+                return
+            }
+            self.pointer -= 1
+        }
+    }
+
+    // Move to the binding before the top of the stack.
+    unsafe fn step_prev(&mut self) {
+        let (page_off, off) = txn::offsets(self.stack[self.pointer - 1]);
+        let page = self.txn.load_page(page_off);
+        let prev = txn::prev_binding(self.txn, &page, off);
+        self.stack[self.pointer - 1] = page_off | (prev as u64);
+        // Everything in the page below the previous binding comes
+        // before the current one.
+        let child = txn::right_child(&page, prev);
+        if child != 0 {
+            self.descend_last(child)
+        } else {
+            self.up_prev()
+        }
+    }
+
+    // Move to the last binding of the tree rooted at `page_off`.
+    unsafe fn descend_last(&mut self, mut page_off: u64) {
+        while page_off != 0 {
+            let page = self.txn.load_page(page_off);
+            let last = txn::last_binding(&page);
+            self.stack[self.pointer] = page_off | (last as u64);
+            self.pointer += 1;
+            page_off = txn::right_child(&page, last);
+        }
+        self.up_prev()
+    }
+
+    // If we're at the beginning of a page, the previous binding is
+    // the one whose right child is that page.
+    unsafe fn up_prev(&mut self) {
+        while self.pointer > 0 {
+            let (_, off) = txn::offsets(self.stack[self.pointer - 1]);
+            if off != FIRST_HEAD {
+                return
+            }
+            self.pointer -= 1
+        }
+    }
+}
@@ -488,6 +488,10 @@ fn delete<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, comp:C,
                         try!(cow_pinpointing(rng, txn, page, &levels, &mut new_levels, true, free_value, 0))
                     };
                 debug!("page={:?}", page);
+                match comp {
+                    C::Smallest => {},
+                    _ => txn.path_start(page.page_offset(), new_levels[0])
+                }
                 Ok(Res::Ok { page:page })
             }
         },
@@ -539,6 +543,7 @@ fn delete<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, comp:C,
                 } else {
                     try!(cow_pinpointing(rng, txn, page, &levels, &mut new_levels, false, false, child_page.page_offset()))
                 };
+            txn.path_push(page.page_offset(), new_levels[0]);
             Ok(Res::Ok { page:page })
         },
         Some(Res::Nothing {.. }) | None => {
@@ -573,8 +578,8 @@ fn delete<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, comp:C,
 
 pub fn del<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, db:&mut Db, key:&[u8], value:Option<&[u8]>)->Result<bool,Error> {
 
-    assert!(key.len() < MAX_KEY_SIZE);
-    let root_page = Cow { cow: txn.txn.load_cow_page(db.root) };
+    debug_assert!(key.len() < MAX_KEY_SIZE);
+    let root_page = txn.load_cow_page(db.root);
 
     let comp = if let Some(value) = value {
         C::KV { key: key,
@@ -582,6 +587,7 @@ pub fn del<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, db:&mut Db, key:&[u8], value
     } else {
         C::K { key:key }
     };
+    txn.path_clear();
     unsafe {
         debug!("root: {:?}", root_page);
         match try!(delete(rng,txn, root_page, comp, false)) {
@@ -592,9 +598,13 @@ pub fn del<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, db:&mut Db, key:&[u8], value
                 if next == NIL && next_page != 0 {
                     db.root = next_page;
                     try!(free(rng, txn, page.page_offset()));
+                    if let Some(ref mut path) = txn.path {
+                        path.pop();
+                    }
                 } else {
                     db.root = page.page_offset();
                 }
+                update_counts(txn, db.root);
                 Ok(true)
             },
             Res::Underfull { page, delete, merged, must_be_dup } => {
@@ -623,8 +633,13 @@ pub fn del<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, db:&mut Db, key:&[u8], value
                     db.root = next_page;
                     try!(free(rng, txn, page.page_offset()));
                 } else {
+                    if next_page == 0 {
+                        // The root is a leaf, the binding was deleted here.
+                        txn.path_start(page.page_offset(), new_levels[0])
+                    }
                     db.root = page.page_offset();
                 }
+                update_counts(txn, db.root);
                 Ok(true)
             },
             Res::Nothing { .. } => {
@@ -633,6 +648,7 @@ pub fn del<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, db:&mut Db, key:&[u8], value
             x => {
                 debug!("root split");
                 db.root = try!(root_split(rng,txn,x)).page_offset();
+                update_counts(txn, db.root);
                 Ok(true)
             }
         }
@@ -641,11 +657,20 @@ pub fn del<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, db:&mut Db, key:&[u8], value
 }
 
 pub fn replace<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, db: &mut Db, key: &[u8], value: &[u8])->Result<(),Error> {
-    try!(del(rng,txn,db,key,None));
-    try!(put(rng,txn,db,key,value));
+    try!(put_(rng, txn, db, key, value, Mode::Replace, None));
     Ok(())
 }
 
+/// Replace the smallest binding of `key` according to `mode`
+/// (`Mode::Replace` or `Mode::IfEqual`), in a single descent of the
+/// tree unless the new value is not smaller than the next value of
+/// `key` (see `put_`). Returns the previous value, if any.
+pub fn replace_smallest<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, db: &mut Db, key: &[u8], value: &[u8], mode:Mode)->Result<Option<Vec<u8>>,Error> {
+    let mut old = None;
+    try!(put_(rng, txn, db, key, value, mode, Some(&mut old)));
+    Ok(old)
+}
+
 
 fn drop_page<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, page:u64)->Result<(),Error> {
     let mut rc = if let Some(rc) = txn.rc() { rc } else { try!(txn.create_db()) };
@@ -656,6 +681,7 @@ fn drop_page<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, page:u64)->Result<(),Erro
         } else {
             try!(txn.replace_u64(rng, &mut rc, page, count-1));
         }
+        txn.set_rc(rc);
     } else {
         let page = txn.load_page(page);
         for (_ , _, value, r) in PageIterator::new(&page,0) {
@@ -681,6 +707,7 @@ pub fn clear<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, db: &mut Db)->Result<(),E
         decr_rc(rng, txn, db.root)
     } else {
         let page = txn.load_cow_page(db.root);
+        let counted = page.is_counted();
         for (_ , _, value, r) in PageIterator::new(&page,0) {
             if let UnsafeValue::O { offset, len } = value {
                 try!(free_value(rng, txn, offset, len))
@@ -690,10 +717,12 @@ pub fn clear<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, db: &mut Db)->Result<(),E
         match page.cow {
             super::transaction::Cow::Page(p0) => {
                 unsafe { super::transaction::free(&mut txn.txn, p0.offset) }
-                db.root = try!(txn.alloc_page()).page_offset();
+                let mut root = try!(txn.alloc_page());
+                root.init_with(counted);
+                db.root = root.page_offset();
             }
             super::transaction::Cow::MutPage(p0) => {
-                (MutPage { page:p0 }).init()
+                (MutPage { page:p0 }).init_with(counted)
             }
         }
         Ok(())
@@ -742,17 +771,17 @@ fn test_delete_leaf() {
                 } else {
                     UnsafeValue::S { p:value.as_ptr(), len:value.len() as u32 }
                 };
-                match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false) {
-                    Ok(Res::Ok { page:page_ }) => {
+                match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false, Mode::Insert, None, None) {
+                    Ok(Inserted::Res(Res::Ok { page:page_ })) => {
                         page = page_
                     },
-                    Ok(Res::Underfull { page:page_, .. }) => {
+                    Ok(Inserted::Res(Res::Underfull { page:page_, .. })) => {
                         page = page_.unwrap_mut();
                     },
-                    Ok(Res::Nothing { page:page_ }) => {
+                    Ok(Inserted::Res(Res::Nothing { page:page_ })) => {
                         page = page_.unwrap_mut()
                     },
-                    Ok(x) => {
+                    Ok(Inserted::Res(x)) => {
                         page = root_split(&mut rng, &mut txn, x).unwrap()
                     },
                     _ => panic!("")
@@ -762,7 +791,7 @@ fn test_delete_leaf() {
         }
         insertions.sort();
 
-        let db = Db { root_num: -1, root: page.page_offset() };
+        let db = Db { root_num: -1, root: page.page_offset(), unique: false };
         txn.debug(&[&db], tmp_path.join("before"), false, false);
         // Delete the 10th smallest entry.
         {
@@ -777,7 +806,7 @@ fn test_delete_leaf() {
                 _ => panic!("")
             }
         }
-        let db = Db { root_num: -1, root: page.page_offset() };
+        let db = Db { root_num: -1, root: page.page_offset(), unique: false };
         txn.debug(&[&db], tmp_path.join("after"), false, false);
         println!("tmp: {:?}", tmp_path);
     }
@@ -826,18 +855,18 @@ fn test_delete_root() {
                 } else {
                     UnsafeValue::S { p:value.as_ptr(), len:value.len() as u32 }
                 };
-                match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false) {
-                    Ok(Res::Ok { page:page_ }) => {
+                match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false, Mode::Insert, None, None) {
+                    Ok(Inserted::Res(Res::Ok { page:page_ })) => {
                         page = page_
                     },
-                    Ok(Res::Underfull { page:page_, .. }) => {
+                    Ok(Inserted::Res(Res::Underfull { page:page_, .. })) => {
                         page = page_.unwrap_mut();
                     },
-                    Ok(Res::Nothing { page:page_ }) => {
+                    Ok(Inserted::Res(Res::Nothing { page:page_ })) => {
                         //println!("already present");
                         page = page_.unwrap_mut()
                     },
-                    Ok(x) => {
+                    Ok(Inserted::Res(x)) => {
                         page = root_split(&mut rng, &mut txn, x).unwrap()
                     },
                     _ => panic!("")
@@ -845,7 +874,7 @@ fn test_delete_root() {
             }
             insertions.push((key_,value_))
         }
-        let db = Db { root_num: -1, root: page.page_offset() };
+        let db = Db { root_num: -1, root: page.page_offset(), unique: false };
         txn.debug(&[&db], tmp_path.join("before"), false, false);
         // Delete an entry in the root.
         {
@@ -863,7 +892,7 @@ fn test_delete_root() {
             }
         }
         debug!("delete done, debugging");
-        let db = Db { root_num: -1, root: page.page_offset() };
+        let db = Db { root_num: -1, root: page.page_offset(), unique: false };
         txn.debug(&[&db], tmp_path.join("after"), false, false);
     }
     std::mem::forget(tmp);
@@ -916,33 +945,34 @@ fn test_delete_all(n:usize, keysize:usize, valuesize:usize, sorted:Sorted) {
                 } else {
                     UnsafeValue::S { p:value.as_ptr(), len:value.len() as u32 }
                 };
-                match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false).unwrap() {
-                    Res::Ok { page:page_ } => {
+                match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false, Mode::Insert, None, None).unwrap() {
+                    Inserted::Res(Res::Ok { page:page_ }) => {
                         page = page_
                     },
-                    Res::Underfull { .. } => {
+                    Inserted::Res(Res::Underfull { .. }) => {
                         unreachable!()
                     },
-                    Res::Nothing { page:page_ } => {
+                    Inserted::Res(Res::Nothing { page:page_ }) => {
                         //println!("already present");
                         page = page_.unwrap_mut()
                     },
-                    x => {
+                    Inserted::Res(x) => {
                         debug!("root split");
                         page = root_split(&mut rng, &mut txn, x).unwrap()
                     },
+                    _ => unreachable!()
                 }
                 value
             };
             debug!("put i = {:?}", i);
             debug!("key = {:?}", key_);
 
-            let db = Db { root_num: -1, root: page.page_offset() };
+            let db = Db { root_num: -1, root: page.page_offset(), unique: false };
             txn.debug(&[&db], (&tmp_path).join(format!("before_{}", i)), false, false);
 
             insertions.push((key_,value_, value))
         }
-        let db = Db { root_num: -1, root: page.page_offset() };
+        let db = Db { root_num: -1, root: page.page_offset(), unique: false };
         txn.debug(&[&db], (&tmp_path).join("before"), false, false);
 
         match sorted {
@@ -998,12 +1028,12 @@ fn test_delete_all(n:usize, keysize:usize, valuesize:usize, sorted:Sorted) {
                 Res::Nothing{..} => unreachable!(),
                 x => page = root_split(&mut rng, &mut txn, x).unwrap(),
             }
-            let db = Db { root_num: -1, root: page.page_offset() };
+            let db = Db { root_num: -1, root: page.page_offset(), unique: false };
             txn.debug(&[&db], (&tmp_path).join(format!("after_{}", i)), false, false);
         }
         debug!("delete done, debugging");
         
-        let db = Db { root_num: -1, root: page.page_offset() };
+        let db = Db { root_num: -1, root: page.page_offset(), unique: false };
         for _ in txn.iter(&db, b"", None) {
             panic!("Database not empty")
         }
@@ -0,0 +1,179 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Group commit: several threads submit closures, the first one to
+// take the `leader` lock runs all pending closures in a single
+// mutable transaction, each of them in its own child transaction,
+// and then commits once for the whole batch.
+
+use std;
+use std::any::Any;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use super::{Env, MutTxn, Error};
+use super::transaction;
+
+/// Child transaction handed to the closures submitted to a `GroupCommit`.
+pub type GroupTxn<'env, 'txn> = MutTxn<'env, &'txn mut transaction::MutTxn<'env, ()>>;
+
+trait Job<'env>: Send {
+    /// Run the closure in a child transaction of `txn`, committing the child if the closure succeeds.
+    fn run(&mut self, txn: &mut MutTxn<'env, ()>);
+    /// Send the result back to the caller, once the whole batch has been committed (or not).
+    fn finish(&mut self, commit: Result<(), &Error>);
+}
+
+// The outcome of a closure: either its result, or the payload of its
+// panic, resumed in the thread that submitted it.
+type Outcome<R> = Result<Result<R, Error>, Box<dyn Any + Send>>;
+
+struct Submission<F, R> {
+    f: Option<F>,
+    result: Option<Outcome<R>>,
+    sender: Sender<Outcome<R>>,
+}
+
+impl<'env, F, R> Job<'env> for Submission<F, R>
+    where F: for<'txn> FnOnce(&mut GroupTxn<'env, 'txn>) -> Result<R, Error> + Send,
+          R: Send
+{
+    fn run(&mut self, txn: &mut MutTxn<'env, ()>) {
+        if let Some(f) = self.f.take() {
+            let result = match txn.mut_txn_begin() {
+                Ok(mut child) => {
+                    // Dropping the child aborts it, leaving the other
+                    // closures of the batch untouched, also when `f`
+                    // panics.
+                    match catch_unwind(AssertUnwindSafe(|| f(&mut child))) {
+                        Ok(Ok(x)) => Ok(child.commit().map(|()| x)),
+                        Ok(Err(e)) => Ok(Err(e)),
+                        Err(payload) => Err(payload),
+                    }
+                }
+                Err(e) => Ok(Err(e)),
+            };
+            self.result = Some(result)
+        }
+    }
+    fn finish(&mut self, commit: Result<(), &Error>) {
+        let result = match (self.result.take(), commit) {
+            (Some(Err(payload)), _) => Err(payload),
+            (Some(Ok(Err(e))), _) => Ok(Err(e)),
+            (Some(Ok(Ok(x))), Ok(())) => Ok(Ok(x)),
+            (_, Err(e)) => Ok(Err(copy_error(e))),
+            (None, Ok(())) => unreachable!(),
+        };
+        // The caller might have gone away, in which case nobody needs the result.
+        self.sender.send(result).unwrap_or(())
+    }
+}
+
+fn copy_error(e: &Error) -> Error {
+    match *e {
+        Error::IO(ref err) => Error::IO(std::io::Error::new(err.kind(), format!("{}", err))),
+        Error::NotEnoughSpace => Error::NotEnoughSpace,
+        Error::Poison => Error::Poison,
+        Error::Conflict => Error::Conflict,
+        Error::KeyTooLarge => Error::KeyTooLarge,
+        Error::ValueTooLarge => Error::ValueTooLarge,
+        Error::InvalidPage(off) => Error::InvalidPage(off),
+        Error::TxnTooLarge => Error::TxnTooLarge,
+        Error::InvalidSavepoint => Error::InvalidSavepoint,
+        Error::UnsupportedVersion(v) => Error::UnsupportedVersion(v),
+        Error::InvalidRoot(num) => Error::InvalidRoot(num),
+    }
+}
+
+/// A group of writers sharing their commits. Closures submitted from
+/// different threads are applied back-to-back in the same mutable
+/// transaction, and written to disk with a single flush.
+pub struct GroupCommit<'env> {
+    env: &'env Env,
+    queue: Mutex<Vec<Box<dyn Job<'env> + 'env>>>,
+    leader: Mutex<()>,
+}
+
+impl<'env> GroupCommit<'env> {
+    #[doc(hidden)]
+    pub fn new(env: &'env Env) -> GroupCommit<'env> {
+        GroupCommit {
+            env: env,
+            queue: Mutex::new(Vec::new()),
+            leader: Mutex::new(()),
+        }
+    }
+
+    /// Run `f` in a child transaction of the next batch, and wait
+    /// until that batch is committed. If `f` returns an error, its
+    /// child transaction is aborted, and the other closures of the
+    /// batch are not affected. If the commit of the batch fails, all
+    /// the closures of the batch get the error. If `f` panics, the
+    /// panic is resumed in the calling thread, after the rest of the
+    /// batch has been committed.
+    pub fn submit<R, F>(&self, f: F) -> Result<R, Error>
+        where F: for<'txn> FnOnce(&mut GroupTxn<'env, 'txn>) -> Result<R, Error> + Send + 'env,
+              R: Send + 'env
+    {
+        let (sender, receiver) = channel();
+        {
+            let mut queue = try!(self.queue.lock());
+            queue.push(Box::new(Submission {
+                f: Some(f),
+                result: None,
+                sender: sender,
+            }))
+        }
+        let leader = try!(self.leader.lock());
+        // A previous leader might have committed our closure while we were waiting.
+        if let Ok(outcome) = receiver.try_recv() {
+            std::mem::drop(leader);
+            return unwrap_outcome(outcome);
+        }
+        let mut jobs = {
+            let mut queue = try!(self.queue.lock());
+            std::mem::take(&mut *queue)
+        };
+        debug!("group commit: {:?} jobs", jobs.len());
+        match self.env.mut_txn_begin() {
+            Ok(mut txn) => {
+                for job in jobs.iter_mut() {
+                    job.run(&mut txn)
+                }
+                match txn.commit() {
+                    Ok(()) => {
+                        for job in jobs.iter_mut() {
+                            job.finish(Ok(()))
+                        }
+                    }
+                    Err(e) => {
+                        for job in jobs.iter_mut() {
+                            job.finish(Err(&e))
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                for job in jobs.iter_mut() {
+                    job.finish(Err(&e))
+                }
+            }
+        }
+        // Resuming a panic must not poison the lock for the next leaders.
+        std::mem::drop(leader);
+        // Our own closure was in this batch, unless a previous leader
+        // went away without answering.
+        match receiver.recv() {
+            Ok(outcome) => unwrap_outcome(outcome),
+            Err(_) => Err(Error::Poison),
+        }
+    }
+}
+
+fn unwrap_outcome<R>(outcome: Outcome<R>) -> Result<R, Error> {
+    match outcome {
+        Ok(result) => result,
+        Err(payload) => resume_unwind(payload),
+    }
+}
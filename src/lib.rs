@@ -49,13 +49,13 @@
 //!    let dir = tempdir::TempDir::new("pijul").unwrap();
 //!    let env = sanakirja::Env::new(dir.path(),100).unwrap();
 //!    let mut txn = env.mut_txn_begin().unwrap();
-//!    let mut root = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+//!    let mut root = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
 //!    txn.put(&mut rng, &mut root, b"test key", b"test value").unwrap();
-//!    txn.set_root(0,root);
+//!    txn.set_root(0,root).unwrap();
 //!    txn.commit().unwrap();
 //!
 //!    let txn = env.txn_begin().unwrap();
-//!    let root = txn.root(0).unwrap();
+//!    let root = txn.root(0).unwrap().unwrap();
 //!    assert!(txn.get(&root, b"test key",None).and_then(|mut x| x.next()) == Some(b"test value"))
 //! }
 //! ```
@@ -70,21 +70,76 @@ extern crate rustc_serialize;
 extern crate log;
 extern crate fs2;
 extern crate memmap;
+#[cfg(target_os = "linux")]
+extern crate libc;
 
-use rand::Rng;
+use rand::{Rng, SeedableRng, XorShiftRng};
 use std::path::Path;
+use std::ops::Bound;
+use std::collections::HashSet;
 pub mod transaction;
 
-pub use transaction::{Statistics,Error};
+pub use transaction::{Statistics,TxnStatistics,Error,SavepointId};
 use transaction::Commit;
 mod txn;
-pub use txn::{MutTxn, Txn, Value, Db, Iter};
+pub use txn::{MutTxn, Txn, Value, Values, Db, Iter, IterToken, MAX_KEY_SIZE, MAX_ROOTS};
 use txn::{P, LoadPage};
 mod put;
 
 mod merge;
 mod rebalance;
 mod del;
+mod group_commit;
+pub use group_commit::{GroupCommit, GroupTxn};
+mod reader_pool;
+pub use reader_pool::ReaderPool;
+mod cursor;
+pub use cursor::Cursor;
+
+/// Options for opening an environment, see `Env::new_with_options`.
+#[derive(Debug, Clone, Copy)]
+pub struct EnvOptions {
+    /// Size of the file, as a number of blocks (see `Env::new`).
+    pub size: u64,
+    /// Seed of the generators owned by mutable transactions, used by
+    /// methods such as `MutTxn::put_auto`. If `None`, each
+    /// transaction is seeded randomly. Else, each transaction (and
+    /// each child transaction) draws the same numbers every time the
+    /// environment is opened with the same seed.
+    pub seed: Option<u64>,
+    /// If `true`, the layout of the skip lists in each page depends
+    /// only on the bindings inserted, and not on the generators
+    /// passed to (or owned by) transactions. Then, the same sequence
+    /// of transactions, started from the same file, yields the same
+    /// file, byte for byte. This does not hold for writers started
+    /// with `mut_txn_begin_roots`, whose allocations depend on the
+    /// order in which they run.
+    pub deterministic: bool,
+}
+
+impl EnvOptions {
+    /// Default options for a file of `size` blocks.
+    pub fn new(size: u64) -> EnvOptions {
+        EnvOptions { size: size, seed: None, deterministic: false }
+    }
+}
+
+/// Options for creating a database, see `MutTxn::create_db_with_options`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DbOptions {
+    /// At most one value per key, see `MutTxn::create_unique_db`.
+    /// The root of unique databases is marked with a bit that older
+    /// versions of this crate would read as part of its offset, so
+    /// files containing them cannot be opened by these versions.
+    pub unique: bool,
+    /// Store the number of bindings below each page, so that `len`
+    /// is O(1), and `rank`, `nth` and `count_range` are
+    /// O(log |```db```|). On other databases, these methods walk the
+    /// database. Counted pages have 8 bytes less for bindings, and
+    /// files containing them cannot be opened by older versions of
+    /// this crate.
+    pub counted: bool,
+}
 
 /// Environment, essentially containing locks and mmaps.
 pub struct Env {
@@ -98,6 +153,14 @@ impl Env {
         transaction::Env::new(file, size*(1<<12)).and_then(|env| Ok(Env { env: env }))
     }
 
+    /// Creates an environment with the given options.
+    pub fn new_with_options<P: AsRef<Path>>(file: P, options: EnvOptions) -> Result<Env, Error> {
+        let mut env = try!(transaction::Env::new(file, options.size*(1<<12)));
+        env.seed = options.seed;
+        env.deterministic = options.deterministic;
+        Ok(Env { env: env })
+    }
+
     /// Start an immutable transaction.
     pub fn txn_begin<'env>(&'env self) -> Result<Txn<'env>,Error> {
         Ok(Txn {
@@ -108,10 +171,37 @@ impl Env {
     /// Start a mutable transaction.
     pub fn mut_txn_begin<'env>(&'env self) -> Result<MutTxn<'env,()>,Error> {
         let txn = try!(self.env.mut_txn_begin());
+        let rng = txn_rng(&self.env, txn.id());
         Ok(MutTxn {
             txn: txn,
             protected_pages: [0;2],
-            free_protected: [false;2]
+            free_protected: [false;2],
+            rng: rng,
+            touched: HashSet::new(),
+            path: None,
+        })
+    }
+    /// Start a mutable transaction that can only change the given
+    /// root databases (see `MutTxn::set_root`). Transactions started
+    /// this way on disjoint sets of roots run concurrently, and are
+    /// only serialized when they commit. Starting one on a root
+    /// already used by another one returns `Error::Conflict`, and so
+    /// does committing after changing a root that was not declared.
+    ///
+    /// Since the reference counts of pages are shared by all
+    /// databases, modifying a database forked with `fork_db` from
+    /// such a transaction will conflict at commit.
+    pub fn mut_txn_begin_roots<'env>(&'env self, roots:&[usize]) -> Result<MutTxn<'env,()>,Error> {
+        let roots:Vec<isize> = roots.iter().map(|&r| (r+1) as isize).collect();
+        let txn = try!(self.env.mut_txn_begin_roots(&roots));
+        let rng = txn_rng(&self.env, txn.id());
+        Ok(MutTxn {
+            txn: txn,
+            protected_pages: [0;2],
+            free_protected: [false;2],
+            rng: rng,
+            touched: HashSet::new(),
+            path: None,
         })
     }
     /// Returns statistics about pages. Useful for debugging or performance analysis.
@@ -130,27 +220,108 @@ impl Env {
         Ok(stats)
     }
 
+    /// Start a group of writers sharing their commits. See `GroupCommit::submit`.
+    pub fn group_commit<'env>(&'env self) -> GroupCommit<'env> {
+        GroupCommit::new(self)
+    }
+
+    /// Wait until a transaction is committed after the one with
+    /// identifier `since` (see `Txn::id`), possibly by another
+    /// process, or until `timeout` expires. Returns the identifier of
+    /// the last committed transaction, or `None` on timeout.
+    pub fn wait_for_commit(&self, since: u64, timeout: std::time::Duration) -> Result<Option<u64>,Error> {
+        self.env.wait_for_commit(since, timeout)
+    }
+
+    /// Start a pool of read transactions, sharing snapshots between
+    /// threads. See `ReaderPool::get`.
+    pub fn reader_pool<'env>(&'env self) -> ReaderPool<'env> {
+        ReaderPool::new(self)
+    }
+}
+
+// Generator of a new top-level mutable transaction. With a fixed
+// seed, it depends only on the seed and on the identifier of the
+// last commit.
+fn txn_rng(env:&transaction::Env, id:u64) -> XorShiftRng {
+    if let Some(seed) = env.seed {
+        seeded_rng(seed ^ id.wrapping_mul(0x9e3779b97f4a7c15))
+    } else {
+        rand::weak_rng()
+    }
+}
+
+// Generator of the levels of the skip lists changed by an operation
+// on `key` and `value`, in deterministic mode. The hash is FNV-1a,
+// which, unlike `std`'s hashers, is stable across platforms and
+// compiler versions.
+fn levels_rng(key:&[u8], value:Option<&[u8]>) -> XorShiftRng {
+    // Keys are shorter than MAX_KEY_SIZE, their length fits in two bytes.
+    let len = [key.len() as u8, (key.len() >> 8) as u8];
+    let mut h:u64 = 0xcbf29ce484222325;
+    for &x in len.iter().chain(key.iter()).chain(value.unwrap_or(&[]).iter()) {
+        h = (h ^ x as u64).wrapping_mul(0x100000001b3)
+    }
+    seeded_rng(h)
+}
+
+// Spread the bits of `seed` with splitmix64. The state of a XorShift
+// generator is its last four outputs, so seeding a child generator
+// directly from its parent would repeat the parent's numbers.
+fn seeded_rng(seed:u64) -> XorShiftRng {
+    let mut x = seed;
+    let mut state = [0u32;4];
+    for s in state.iter_mut() {
+        x = x.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = x;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        *s = (z ^ (z >> 31)) as u32;
+    }
+    if state == [0;4] {
+        // XorShift needs a nonzero state.
+        state[0] = 1
+    }
+    XorShiftRng::from_seed(state)
 }
 
 impl<'env,T> MutTxn<'env,T> {
     /// Creates a new database, complexity O(1).
     pub fn create_db(&mut self) -> Result<Db,Error> {
+        self.create_db_with_options(DbOptions::default())
+    }
+
+    /// Creates a new database with the given options, complexity O(1).
+    pub fn create_db_with_options(&mut self, options: DbOptions) -> Result<Db,Error> {
+        try!(self.txn.check_poison());
         let mut db = try!(self.alloc_page());
-        db.init();
-        Ok(Db { root_num:-1, root: db.page_offset() })
+        if options.counted || options.unique {
+            self.txn.upgrade_version()
+        }
+        db.init_with(options.counted);
+        Ok(Db { root_num:-1, root: db.page_offset(), unique: options.unique })
+    }
+
+    /// Creates a new database with at most one value per key, on
+    /// which `put` replaces the value of an existing key instead of
+    /// adding another binding. Complexity O(1).
+    pub fn create_unique_db(&mut self) -> Result<Db,Error> {
+        self.create_db_with_options(DbOptions { unique: true, ..DbOptions::default() })
     }
 
     /// Produce an independent fork of a database. The two databases share their bindings at the time of the fork, and can safely be considered separate databases after the fork. Complexity: linear in the number of blocks referenced at least twice (smaller than the total number of allocated blocks).
     pub fn fork_db<R:Rng>(&mut self, rng:&mut R, db:&Db) -> Result<Db,Error> {
-        try!(put::fork_db(rng, self, db.root));
-        Ok(Db { root_num:-1, root: db.root })
+        try!(self.check_page(db.root));
+        try!(self.poison_on_error(|txn| put::fork_db(rng, txn, db.root)));
+        Ok(Db { root_num:-1, root: db.root, unique: db.unique })
     }
 
     /// Specialized version of ```put``` to register the name of a database. Argument ```db``` can be the root database (as in LMDB) or any other database. Complexity O(log |```db```|).
     pub fn put_db<R:Rng>(&mut self, rng:&mut R, db: &mut Db, key: &[u8], value: Db)->Result<(),Error> {
+        try!(self.check_page(value.root));
         let mut val: [u8; 8] = [0; 8];
         unsafe {
-            *(val.as_mut_ptr() as *mut u64) = value.root.to_le();
+            *(val.as_mut_ptr() as *mut u64) = value.stored().to_le();
         }
         try!(self.replace(rng, db, key, &val));
         //self.txn.set_root(db.root_num, db.root);
@@ -159,28 +330,115 @@ impl<'env,T> MutTxn<'env,T> {
 
     /// Drops a database. Complexity O(|```db```|).
     pub fn drop<R:Rng>(&mut self, rng:&mut R, db: Db)->Result<(),Error> {
-        del::drop(rng, self, db)
+        try!(self.check_page(db.root));
+        self.poison_on_error(|txn| del::drop(rng, txn, db))
     }
 
     /// Empties a database, without dropping it. Complexity O(|```db```|).
     pub fn clear<R:Rng>(&mut self, rng:&mut R, db: &mut Db)->Result<(),Error> {
-        del::clear(rng, self, db)
+        try!(self.check_page(db.root));
+        self.poison_on_error(|txn| del::clear(rng, txn, db))
     }
 
 
     /// Add a binding to a B tree. Complexity O(log |```db```|).
+    ///
+    /// In a unique database (see `create_unique_db`), this replaces
+    /// the value of `key` if it is already bound, in the same descent
+    /// of the tree. Returns `false` if the binding was already there.
+    ///
+    /// This is atomic: if it fails, for instance with
+    /// `Error::NotEnoughSpace`, `db` and the transaction are left
+    /// unchanged, and can still be used.
+    ///
+    /// Keys must be shorter than `MAX_KEY_SIZE` bytes, else
+    /// `Error::KeyTooLarge` is returned. If `db` is not a database of
+    /// this environment, `Error::InvalidPage` is returned. Only the
+    /// root of `db` is checked: the pages below it are trusted, and
+    /// a corrupted file can still cause a panic.
     pub fn put<R:Rng>(&mut self, r:&mut R, db: &mut Db, key: &[u8], value: &[u8])->Result<bool,Error> {
-        put::put(r, self, db, key, value)
+        try!(self.check_input(db, key, Some(value)));
+        if self.txn.env.deterministic {
+            let mut r = levels_rng(key, Some(value));
+            self.atomically(db, |txn, db| put::put(&mut r, txn, db, key, value))
+        } else {
+            self.atomically(db, |txn, db| put::put(r, txn, db, key, value))
+        }
     }
 
-    /// Replace the binding for a key. At the moment, this is actually no more than `del` and `put` in a row: if there are more than one binding for that key, replace the smallest one, in lexicographical order. Complexity O(log |```db```|).
+    /// Replace the binding for a key: if there are more than one binding for that key, replace the smallest one, in lexicographical order. This descends the tree only once, and rewrites the value in its page if the new one is not larger, unless the new value is not smaller than the next value of that key, in which case the binding is deleted and inserted again. On unique databases, this is the same as `put`. Complexity O(log |```db```|). Atomic, like `put`.
     pub fn replace<R:Rng>(&mut self, r:&mut R, db: &mut Db, key: &[u8], value: &[u8])->Result<(),Error> {
-        del::replace(r, self, db, key, value)
+        try!(self.check_input(db, key, Some(value)));
+        if self.txn.env.deterministic {
+            let mut r = levels_rng(key, Some(value));
+            self.atomically(db, |txn, db| del::replace(&mut r, txn, db, key, value))
+        } else {
+            self.atomically(db, |txn, db| del::replace(r, txn, db, key, value))
+        }
+    }
+
+    /// Add a binding only if `key` has no binding yet, and return
+    /// whether it was added. Complexity O(log |```db```|). Atomic,
+    /// like `put`.
+    pub fn put_if_absent<R:Rng>(&mut self, r:&mut R, db: &mut Db, key: &[u8], value: &[u8])->Result<bool,Error> {
+        try!(self.check_input(db, key, Some(value)));
+        if self.txn.env.deterministic {
+            let mut r = levels_rng(key, Some(value));
+            self.atomically(db, |txn, db| put::put_(&mut r, txn, db, key, value, put::Mode::IfAbsent, None))
+        } else {
+            self.atomically(db, |txn, db| put::put_(r, txn, db, key, value, put::Mode::IfAbsent, None))
+        }
+    }
+
+    /// Replace the value of the smallest binding of `key` by `new`,
+    /// only if it is `expected`, and return whether it was.
+    /// Complexity O(log |```db```|), see `replace`. Atomic, like `put`.
+    pub fn compare_and_swap<R:Rng>(&mut self, r:&mut R, db: &mut Db, key: &[u8], expected: &[u8], new: &[u8])->Result<bool,Error> {
+        try!(self.check_input(db, key, Some(new)));
+        let mode = put::Mode::IfEqual(expected);
+        let old =
+            if self.txn.env.deterministic {
+                let mut r = levels_rng(key, Some(new));
+                try!(self.atomically(db, |txn, db| del::replace_smallest(&mut r, txn, db, key, new, mode)))
+            } else {
+                try!(self.atomically(db, |txn, db| del::replace_smallest(r, txn, db, key, new, mode)))
+            };
+        Ok(old.as_ref().map(|x| &x[..]) == Some(expected))
+    }
+
+    /// Same as `replace`, returning the previous value of the
+    /// binding, if any. Complexity O(log |```db```|). Atomic, like
+    /// `put`.
+    pub fn upsert<R:Rng>(&mut self, r:&mut R, db: &mut Db, key: &[u8], value: &[u8])->Result<Option<Vec<u8>>,Error> {
+        try!(self.check_input(db, key, Some(value)));
+        let mode = put::Mode::Replace;
+        let old =
+            if self.txn.env.deterministic {
+                let mut r = levels_rng(key, Some(value));
+                try!(self.atomically(db, |txn, db| del::replace_smallest(&mut r, txn, db, key, value, mode)))
+            } else {
+                try!(self.atomically(db, |txn, db| del::replace_smallest(r, txn, db, key, value, mode)))
+            };
+        Ok(old)
     }
 
-    /// Delete the smallest binding (in lexicographical order) from the map matching the key and value. When the `value` argument is `None`, delete the smallest binding for that key. Complexity O(log |```db```|).
+    /// Delete the smallest binding (in lexicographical order) from the map matching the key and value. When the `value` argument is `None`, delete the smallest binding for that key. Complexity O(log |```db```|). Atomic, like `put`.
     pub fn del<R:Rng>(&mut self, r:&mut R, db: &mut Db, key: &[u8], value: Option<&[u8]>)->Result<bool,Error> {
-        del::del(r, self, db, key, value)
+        try!(self.check_input(db, key, value));
+        if self.txn.env.deterministic {
+            let mut r = levels_rng(key, value);
+            self.atomically(db, |txn, db| del::del(&mut r, txn, db, key, value))
+        } else {
+            self.atomically(db, |txn, db| del::del(r, txn, db, key, value))
+        }
+    }
+
+    /// Open a cursor on a database, to walk it in both directions
+    /// while deleting or replacing bindings. `db` is updated by the
+    /// cursor when it changes the database. Complexity O(1).
+    pub fn cursor<'c>(&'c mut self, db: &'c mut Db) -> Result<Cursor<'c, 'env, T>, Error> {
+        try!(self.check_page(db.root));
+        Ok(Cursor::new(self, db))
     }
 
     /// Specialized version of ```put``` for the case where both the key and value are 64-bits integers. Complexity O(log |```db```|).
@@ -199,7 +457,7 @@ impl<'env,T> MutTxn<'env,T> {
         unsafe {
             *(k.as_mut_ptr() as *mut u64) = key.to_le();
         }
-        self.del(rng, db, &k, None)
+        self.without_path(|txn| txn.del(rng, db, &k, None))
     }
 
     /// Specialized version of ```replace``` for the case where the key is a 64-bits integer. Complexity  O(log |```db```|).
@@ -210,31 +468,234 @@ impl<'env,T> MutTxn<'env,T> {
             *(k.as_mut_ptr() as *mut u64) = key.to_le();
             *(v.as_mut_ptr() as *mut u64) = value.to_le();
         }
-        self.replace(rng, db, &k, &v)
+        self.without_path(|txn| txn.replace(rng, db, &k, &v))
+    }
+
+    /// Same as `fork_db`, using the generator of this transaction
+    /// (see `EnvOptions::seed`).
+    pub fn fork_db_auto(&mut self, db:&Db) -> Result<Db,Error> {
+        self.with_rng(|txn, rng| txn.fork_db(rng, db))
+    }
+
+    /// Same as `put_db`, using the generator of this transaction.
+    pub fn put_db_auto(&mut self, db: &mut Db, key: &[u8], value: Db)->Result<(),Error> {
+        self.with_rng(|txn, rng| txn.put_db(rng, db, key, value))
+    }
+
+    /// Same as `drop`, using the generator of this transaction.
+    pub fn drop_auto(&mut self, db: Db)->Result<(),Error> {
+        self.with_rng(|txn, rng| txn.drop(rng, db))
+    }
+
+    /// Same as `clear`, using the generator of this transaction.
+    pub fn clear_auto(&mut self, db: &mut Db)->Result<(),Error> {
+        self.with_rng(|txn, rng| txn.clear(rng, db))
+    }
+
+    /// Same as `put`, using the generator of this transaction.
+    pub fn put_auto(&mut self, db: &mut Db, key: &[u8], value: &[u8])->Result<bool,Error> {
+        self.with_rng(|txn, rng| txn.put(rng, db, key, value))
+    }
+
+    /// Same as `replace`, using the generator of this transaction.
+    pub fn replace_auto(&mut self, db: &mut Db, key: &[u8], value: &[u8])->Result<(),Error> {
+        self.with_rng(|txn, rng| txn.replace(rng, db, key, value))
+    }
+
+    /// Same as `put_if_absent`, using the generator of this transaction.
+    pub fn put_if_absent_auto(&mut self, db: &mut Db, key: &[u8], value: &[u8])->Result<bool,Error> {
+        self.with_rng(|txn, rng| txn.put_if_absent(rng, db, key, value))
+    }
+
+    /// Same as `compare_and_swap`, using the generator of this transaction.
+    pub fn compare_and_swap_auto(&mut self, db: &mut Db, key: &[u8], expected: &[u8], new: &[u8])->Result<bool,Error> {
+        self.with_rng(|txn, rng| txn.compare_and_swap(rng, db, key, expected, new))
+    }
+
+    /// Same as `upsert`, using the generator of this transaction.
+    pub fn upsert_auto(&mut self, db: &mut Db, key: &[u8], value: &[u8])->Result<Option<Vec<u8>>,Error> {
+        self.with_rng(|txn, rng| txn.upsert(rng, db, key, value))
+    }
+
+    /// Same as `del`, using the generator of this transaction.
+    pub fn del_auto(&mut self, db: &mut Db, key: &[u8], value: Option<&[u8]>)->Result<bool,Error> {
+        self.with_rng(|txn, rng| txn.del(rng, db, key, value))
+    }
+
+    /// Same as `put_u64`, using the generator of this transaction.
+    pub fn put_u64_auto(&mut self, db: &mut Db, key: u64, value: u64)->Result<bool,Error> {
+        self.with_rng(|txn, rng| txn.put_u64(rng, db, key, value))
+    }
+
+    /// Same as `del_u64`, using the generator of this transaction.
+    pub fn del_u64_auto(&mut self, db:&mut Db, key:u64)->Result<bool,Error> {
+        self.with_rng(|txn, rng| txn.del_u64(rng, db, key))
+    }
+
+    /// Same as `replace_u64`, using the generator of this transaction.
+    pub fn replace_u64_auto(&mut self, db: &mut Db, key: u64, value: u64)->Result<(),Error> {
+        self.with_rng(|txn, rng| txn.replace_u64(rng, db, key, value))
+    }
+
+    // Run `f` without recording its path. The reference counts are
+    // updated with `del_u64` and `replace_u64` in the middle of other
+    // operations, whose path a cursor might be recording (see
+    // `Cursor::delete_current`).
+    fn without_path<A, F:FnOnce(&mut Self) -> A>(&mut self, f:F) -> A {
+        let path = self.path.take();
+        let result = f(self);
+        self.path = path;
+        result
+    }
+
+    // Run `f` with the generator of this transaction, which cannot be
+    // borrowed at the same time as the transaction itself.
+    fn with_rng<A, F:FnOnce(&mut Self, &mut XorShiftRng) -> A>(&mut self, f:F) -> A {
+        let mut rng = self.rng.clone();
+        let result = f(self, &mut rng);
+        self.rng = rng;
+        result
+    }
+
+    // Run an operation that might leave the pages of this transaction
+    // half-rewritten if it fails. After a failure, all operations
+    // (including commit) return `Error::Poison`.
+    fn poison_on_error<A, F:FnOnce(&mut Self) -> Result<A, Error>>(&mut self, f:F) -> Result<A, Error> {
+        try!(self.txn.check_poison());
+        let result = f(self);
+        if result.is_err() {
+            self.txn.poison()
+        }
+        result
     }
 
-    /// Set the root database, consuming it.
-    pub fn set_root(&mut self, num:usize, db:Db) {
-        self.txn.set_root((num+1) as isize, db.root)
+    // Validate the arguments of an operation, instead of panicking
+    // deeper in the tree.
+    fn check_input(&self, db:&Db, key:&[u8], value:Option<&[u8]>) -> Result<(), Error> {
+        if key.len() >= txn::MAX_KEY_SIZE {
+            return Err(Error::KeyTooLarge)
+        }
+        if value.map(|v| v.len() > u32::MAX as usize).unwrap_or(false) {
+            return Err(Error::ValueTooLarge)
+        }
+        self.check_page(db.root)
+    }
+
+    // Run an operation on `db` atomically: if it fails, `db` and this
+    // transaction are left as they were before the call, and the
+    // transaction can still be used.
+    fn atomically<A, F:FnOnce(&mut Self, &mut Db) -> Result<A, Error>>(&mut self, db:&mut Db, f:F) -> Result<A, Error> {
+        try!(self.txn.check_poison());
+        if self.txn.in_atomic() {
+            // Operations on the reference counts, within another operation.
+            return f(self, db)
+        }
+        let root = db.root;
+        self.txn.begin_atomic();
+        let result = f(self, db);
+        // The counts of bindings are up to date at the end of each
+        // operation (see `put::update_counts`).
+        self.touched.clear();
+        if result.is_ok() {
+            self.txn.end_atomic()
+        } else {
+            self.txn.abort_atomic();
+            self.protected_pages = [0;2];
+            self.free_protected = [false;2];
+            db.root = root;
+        }
+        result
+    }
+
+    /// Set the root database, consuming it. Returns
+    /// `Error::InvalidRoot` if `num` is not smaller than `MAX_ROOTS`.
+    pub fn set_root(&mut self, num:usize, db:Db) -> Result<(), Error> {
+        if num >= MAX_ROOTS {
+            return Err(Error::InvalidRoot(num))
+        }
+        self.txn.set_root((num+1) as isize, db.stored());
+        Ok(())
     }
 
     /// Create a child transaction, which can be either committed to its parent (but not to the file), or aborted independently from its parent. Complexity O(1).
     pub fn mut_txn_begin<'txn>(&'txn mut self) -> Result<MutTxn<'env,&'txn mut transaction::MutTxn<'env,T>>,Error> {
+        let rng = seeded_rng(self.rng.next_u64());
         let txn = try!(self.txn.mut_txn_begin());
-        Ok(MutTxn { txn: txn, protected_pages: [0;2], free_protected:[false;2] })
+        Ok(MutTxn { txn: txn, protected_pages: [0;2], free_protected:[false;2], rng: rng, touched: HashSet::new(), path: None })
     }
     pub fn abort(self) {
 
     }
+    /// Identifier of the last transaction committed before this one started.
+    pub fn id(&self) -> u64 {
+        self.txn.id()
+    }
+
+    /// Limit the number of pages this transaction can allocate (see
+    /// `dirty_pages`), or remove the limit if `limit` is `None`. When
+    /// an operation would need more pages, it fails with
+    /// `Error::TxnTooLarge`. Since `put`, `del` and `replace` are
+    /// atomic, the transaction can then be committed, and the
+    /// operation retried in a new transaction. Other operations
+    /// poison the transaction instead.
+    ///
+    /// Child transactions started after this count the pages held by
+    /// their parents, and inherit the limit.
+    pub fn set_page_limit(&mut self, limit: Option<usize>) {
+        self.txn.set_page_limit(limit)
+    }
+
+    /// Number of pages allocated by this transaction and its parents,
+    /// not counting the pages they freed and allocated again, nor the
+    /// ones given back by `rollback_to` or by a failed operation.
+    pub fn dirty_pages(&self) -> usize {
+        self.txn.dirty_pages()
+    }
+
+    /// Counters of the pages allocated, copied and freed by this
+    /// transaction, including by the child transactions committed
+    /// into it.
+    pub fn stats(&self) -> TxnStatistics {
+        self.txn.stats
+    }
+
+    /// Record the current state of this transaction, to return to it
+    /// later with `rollback_to`. Complexity O(n) in the number of
+    /// pages allocated by this transaction.
+    pub fn savepoint(&mut self) -> SavepointId {
+        self.txn.savepoint()
+    }
+
+    /// Cancel all the changes made since savepoint `id`, including
+    /// the roots set with `set_root`. The savepoints taken after `id`
+    /// are discarded.
+    ///
+    /// A `Db` changed since the savepoint may point to pages released
+    /// by the rollback: it must not be used anymore, and must be
+    /// reloaded (for instance with `root`) or replaced by a copy
+    /// taken before the savepoint.
+    ///
+    /// Returns `Error::InvalidSavepoint` if `id` was discarded by a
+    /// previous rollback, or taken in another transaction.
+    pub fn rollback_to(&mut self, id: SavepointId) -> Result<(), Error> {
+        self.txn.rollback_to(id)
+    }
 }
 
 pub trait Transaction:LoadPage {
-    /// Load the root database, if there's one.
-    fn root(&self, num:usize) -> Option<Db> {
-        self.root_db_((num+1) as isize)
+    /// Load the root database, if there's one. Returns
+    /// `Error::InvalidRoot` if `num` is not smaller than `MAX_ROOTS`.
+    fn root(&self, num:usize) -> Result<Option<Db>, Error> {
+        if num >= MAX_ROOTS {
+            return Err(Error::InvalidRoot(num))
+        }
+        Ok(self.root_db_((num+1) as isize))
     }
     /// get the smallest value corresponding to a key (or to a key and a value). The return type is an iterator outputting byte slices. Complexity  O(log |```db```|).
     fn get<'a>(&'a self, db: &Db, key: &[u8], value:Option<&[u8]>) -> Option<Value<'a,Self>> {
+        if self.check_page(db.root).is_err() || value.map(|v| v.len() > u32::MAX as usize).unwrap_or(false) {
+            return None
+        }
         unsafe {
             let page = self.load_page(db.root);
             debug!("page = {:?}", page);
@@ -243,8 +704,23 @@ pub trait Transaction:LoadPage {
         }
     }
 
+    /// Get all the values bound to a key, in increasing order (or
+    /// decreasing order, with `rev()`). Complexity O(log |```db```|).
+    fn get_all<'a>(&'a self, db: &Db, key: &[u8]) -> Values<'a,Self> {
+        Values::new(self.iter_range(db, Bound::Included(key), Bound::Included(key)))
+    }
+
+    /// Number of values bound to a key. Complexity O(log |```db```|)
+    /// on counted databases, see `count_range`.
+    fn count_values(&self, db: &Db, key: &[u8]) -> u64 {
+        self.count_range(db, Bound::Included(key), Bound::Included(key))
+    }
+
     /// Open an existing database from the root database.
     fn open_db<'a>(&'a self, root_db:&Db, key: &[u8]) -> Option<Db> {
+        if self.check_page(root_db.root).is_err() {
+            return None
+        }
         self.open_db_(root_db, key)
     }
 
@@ -254,6 +730,9 @@ pub trait Transaction:LoadPage {
                     db: &Db,
                     key: &[u8],
                     value: Option<&[u8]>)->Iter<'a,Self> {
+        if self.check_page(db.root).is_err() || value.map(|v| v.len() > u32::MAX as usize).unwrap_or(false) {
+            return Iter::empty(self)
+        }
         unsafe {
             let page = self.load_page(db.root);
             let value = value.map(|x| txn::UnsafeValue::S { p:x.as_ptr(), len:x.len() as u32 });
@@ -261,6 +740,135 @@ pub trait Transaction:LoadPage {
         }
     }
 
+    /// Return an iterator on a database, starting strictly after the
+    /// position of `token` (see `Iter::position`). The token can come
+    /// from another transaction, and its binding might not be in the
+    /// database anymore. Complexity O(log |```db```|).
+    fn iter_from_token<'a>(&'a self,
+                           db: &Db,
+                           token: &IterToken)->Iter<'a,Self> {
+        if self.check_page(db.root).is_err() {
+            return Iter::empty(self)
+        }
+        unsafe {
+            let page = self.load_page(db.root);
+            self.iter_after_(&page, token)
+        }
+    }
+
+    /// Return an iterator on the bindings of a database whose keys
+    /// are within the given bounds, in increasing order (or
+    /// decreasing order, with `rev()`). Complexity O(log |```db```|).
+    fn iter_range<'a>(&'a self,
+                      db: &Db,
+                      start: Bound<&[u8]>,
+                      end: Bound<&[u8]>)->Iter<'a,Self> {
+        if self.check_page(db.root).is_err() {
+            return Iter::empty(self)
+        }
+        unsafe {
+            let page = self.load_page(db.root);
+            self.iter_range_(&page, start, end)
+        }
+    }
+
+    /// Return an iterator on the bindings of a database whose keys
+    /// start with `prefix`, including all the values of these keys.
+    /// Complexity O(log |```db```|).
+    fn iter_prefix<'a>(&'a self,
+                       db: &Db,
+                       prefix: &[u8])->Iter<'a,Self> {
+        // The first key after all keys starting with `prefix`.
+        let mut end = prefix.to_vec();
+        while let Some(last) = end.pop() {
+            if last < 0xff {
+                end.push(last + 1);
+                return self.iter_range(db, Bound::Included(prefix), Bound::Excluded(&end))
+            }
+        }
+        self.iter_range(db, Bound::Included(prefix), Bound::Unbounded)
+    }
+
+    /// Return an iterator on a database in decreasing order,
+    /// starting with the last binding smaller than or equal to the
+    /// given key and value (or with the last value of `key`, if
+    /// `value` is `None`). Complexity O(log |```db```|).
+    ///
+    /// To start from the end of the database, use `iter(db, b"", None).rev()` instead.
+    fn iter_rev<'a>(&'a self,
+                    db: &Db,
+                    key: &[u8],
+                    value: Option<&[u8]>)->std::iter::Rev<Iter<'a,Self>> {
+        if self.check_page(db.root).is_err() || value.map(|v| v.len() > u32::MAX as usize).unwrap_or(false) {
+            return Iter::empty(self).rev()
+        }
+        unsafe {
+            let page = self.load_page(db.root);
+            let value = value.map(|x| txn::UnsafeValue::S { p:x.as_ptr(), len:x.len() as u32 });
+            self.iter_rev_(&page, key,value).rev()
+        }
+    }
+
+    /// Number of bindings in a database. Complexity O(1) on counted
+    /// databases (see `DbOptions::counted`), O(|```db```|) else.
+    fn len(&self, db: &Db) -> u64 {
+        if self.check_page(db.root).is_err() {
+            return 0
+        }
+        self.count_(db.root)
+    }
+
+    /// Number of bindings strictly smaller than the given key and
+    /// value (or than all the bindings of `key`, if `value` is
+    /// `None`), i.e. the position at which `iter` would start.
+    /// Complexity O(log |```db```|) on counted databases, O(|```db```|) else.
+    fn rank(&self, db: &Db, key: &[u8], value: Option<&[u8]>) -> u64 {
+        if self.check_page(db.root).is_err() || value.map(|v| v.len() > u32::MAX as usize).unwrap_or(false) {
+            return 0
+        }
+        unsafe {
+            let page = self.load_page(db.root);
+            let value = value.map(|x| txn::UnsafeValue::S { p:x.as_ptr(), len:x.len() as u32 });
+            self.rank_(&page, key, value, false)
+        }
+    }
+
+    /// The binding at position `n` of a database, starting from 0, if
+    /// `n` is smaller than its length. Complexity O(log |```db```|)
+    /// on counted databases, O(|```db```|) else.
+    fn nth<'a>(&'a self, db: &Db, n: u64) -> Option<(&'a [u8], Value<'a,Self>)> {
+        if self.check_page(db.root).is_err() {
+            return None
+        }
+        unsafe {
+            let page = self.load_page(db.root);
+            self.nth_(&page, n).map(|(key, value)| (key, Value::from_unsafe(&value, self)))
+        }
+    }
+
+    /// Number of bindings whose keys are within the given bounds,
+    /// counting all the values of each key. Complexity
+    /// O(log |```db```|) on counted databases, O(|```db```|) else.
+    fn count_range(&self, db: &Db, start: Bound<&[u8]>, end: Bound<&[u8]>) -> u64 {
+        if self.check_page(db.root).is_err() {
+            return 0
+        }
+        unsafe {
+            let page = self.load_page(db.root);
+            let start = match start {
+                Bound::Included(key) => self.rank_(&page, key, None, false),
+                Bound::Excluded(key) => self.rank_(&page, key, None, true),
+                Bound::Unbounded => 0,
+            };
+            let end = match end {
+                Bound::Included(key) => self.rank_(&page, key, None, true),
+                Bound::Excluded(key) => self.rank_(&page, key, None, false),
+                Bound::Unbounded => self.count_(db.root),
+            };
+            end.saturating_sub(start)
+        }
+    }
+
 
 }
 
@@ -271,13 +879,48 @@ impl<'env,T> Transaction for MutTxn<'env,T> {}
 
 impl<'env> MutTxn<'env,()> {
     /// Commit the transaction to the file (consuming it). Complexity linear in the number of pages freed by this transaction.
+    ///
+    /// If an operation of this transaction failed, the transaction is
+    /// poisoned, and committing it returns `Error::Poison`.
     pub fn commit(mut self) -> Result<(), transaction::Error> {
         self.txn.commit()
     }
+
+    /// Commit the transaction to the file (consuming it), only if no
+    /// other transaction was committed after the one identified by
+    /// `expected` (see `Txn::id`). Else, return `Error::Conflict`.
+    pub fn commit_if(mut self, expected:u64) -> Result<(), transaction::Error> {
+        self.txn.commit_if(expected)
+    }
+}
+
+impl<'env> Txn<'env> {
+    /// Identifier of the last transaction committed before this one
+    /// started. Identifiers increase by one with each commit.
+    pub fn id(&self) -> u64 {
+        self.txn.id()
+    }
+
+    /// Turn this immutable transaction into a mutable one, keeping
+    /// its snapshot, without holding the writers lock while
+    /// reading. Fails with `Error::Conflict` if another transaction
+    /// was committed since this one started.
+    pub fn upgrade(self) -> Result<MutTxn<'env,()>, Error> {
+        let txn = try!(self.txn.upgrade());
+        let rng = txn_rng(txn.env, txn.id());
+        Ok(MutTxn {
+            txn: txn,
+            protected_pages: [0;2],
+            free_protected: [false;2],
+            rng: rng,
+            touched: HashSet::new(),
+            path: None,
+        })
+    }
 }
 
 impl<'env,'txn,T> MutTxn<'env,&'txn mut transaction::MutTxn<'env,T>> {
-    /// Commit the child transaction to its parent (consuming it). Complexity linear in the number of pages freed by this transaction.
+    /// Commit the child transaction to its parent (consuming it). Complexity linear in the number of pages freed by this transaction. Returns `Error::Poison` if an operation of the child failed, in which case the parent is left unchanged.
     pub fn commit(mut self) -> Result<(), transaction::Error> {
         self.txn.commit()
     }
@@ -297,15 +940,15 @@ mod tests {
         let dir = tempdir::TempDir::new("pijul").unwrap();
         let env = Env::new(dir.path(), 100).unwrap();
         let mut txn = env.mut_txn_begin().unwrap();
-        let mut root = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+        let mut root = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
         println!("root: {:?}", root);
         txn.put(&mut rng, &mut root, b"test key", b"test value").unwrap();
-        txn.set_root(0, root);
+        txn.set_root(0, root).unwrap();
         println!("committing");
         txn.commit().unwrap();
 
         let txn = env.txn_begin().unwrap();
-        let root = txn.root(0);
+        let root = txn.root(0).unwrap();
         println!("root = {:?}", root);
         let root = root.unwrap();
         assert!(txn.get(&root, b"test key",None).and_then(|mut x| x.next()) == Some(b"test value"))
@@ -325,7 +968,7 @@ mod tests {
         let dir = tempdir::TempDir::new("pijul").unwrap();
         let env = Env::new(dir.path(), 100).unwrap();
         let mut txn = env.mut_txn_begin().unwrap();
-        let mut root = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+        let mut root = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
 
         let mut random = Vec::new();
         for _ in 0..300 {
@@ -341,13 +984,13 @@ mod tests {
             txn.put(&mut rng, &mut root, k.as_bytes(), v.as_bytes()).unwrap();
             random.push((k,v));
         }
-        txn.set_root(0, root);
+        txn.set_root(0, root).unwrap();
         println!("committing");
         txn.commit().unwrap();
 
         random.sort();
         let txn = env.txn_begin().unwrap();
-        let root = txn.root(0).unwrap();
+        let root = txn.root(0).unwrap().unwrap();
         txn.debug(&[&root], "/tmp/iter", false, false);
 
         let mut i = 100;
@@ -385,7 +1028,7 @@ mod tests {
         let dir = tempdir::TempDir::new("pijul").unwrap();
         let env = Env::new(dir.path(), 1000).unwrap();
         let mut txn = env.mut_txn_begin().unwrap();
-        let mut root = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+        let mut root = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
 
         let mut bindings = Vec::new();
         for i in 0..500 {
@@ -418,7 +1061,7 @@ mod tests {
         }        
         txn.debug(&[&root], format!("/tmp/debug_{}",i), false, false);
         //println!("{:?}",bindings.len());
-        txn.set_root(0, root);
+        txn.set_root(0, root).unwrap();
         txn.commit().unwrap();
     }
 
@@ -434,34 +1077,1771 @@ mod tests {
         let mut txn = env.mut_txn_begin().unwrap();
         {
             let mut child_txn = txn.mut_txn_begin().unwrap();
-            let mut root = child_txn.root(0).unwrap_or_else(|| child_txn.create_db().unwrap());
+            let mut root = child_txn.root(0).unwrap().unwrap_or_else(|| child_txn.create_db().unwrap());
             child_txn.put(&mut rng, &mut root, b"A", b"Value for A").unwrap();
-            child_txn.set_root(0, root);
+            child_txn.set_root(0, root).unwrap();
             child_txn.commit().unwrap();
         }
         {
             let mut child_txn = txn.mut_txn_begin().unwrap();
-            let mut root = child_txn.root(0).unwrap();
+            let mut root = child_txn.root(0).unwrap().unwrap();
             child_txn.put(&mut rng, &mut root, b"B", b"Value for B").unwrap();
-            child_txn.set_root(0, root);
+            child_txn.set_root(0, root).unwrap();
             //child_txn.abort();
         }
         {
             let mut child_txn = txn.mut_txn_begin().unwrap();
-            let mut root = child_txn.root(0).unwrap_or_else(|| child_txn.create_db().unwrap());
+            let mut root = child_txn.root(0).unwrap().unwrap_or_else(|| child_txn.create_db().unwrap());
             child_txn.put(&mut rng, &mut root, b"C", b"Value for C").unwrap();
-            child_txn.set_root(0, root);
+            child_txn.set_root(0, root).unwrap();
             child_txn.commit().unwrap();
         }
         txn.commit().unwrap();
 
         let txn = env.txn_begin().unwrap();
-        let root = txn.root(0).unwrap();
+        let root = txn.root(0).unwrap().unwrap();
         assert!(txn.get(&root, b"A",None).and_then(|mut x| x.next()) == Some(b"Value for A"));
         assert!(txn.get(&root, b"B",None).is_none());
         assert!(txn.get(&root, b"C",None).and_then(|mut x| x.next()) == Some(b"Value for C"));
     }
 
+    #[test]
+    fn group_commit() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use std;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let group = env.group_commit();
+        let results:Vec<_> = std::thread::scope(|s| {
+            let threads:Vec<_> = (0..20).map(|i| {
+                let group = &group;
+                s.spawn(move || {
+                    group.submit(move |txn| {
+                        let mut rng = rand::thread_rng();
+                        let mut root = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+                        let key = format!("key {}", i);
+                        try!(txn.put(&mut rng, &mut root, key.as_bytes(), b"value"));
+                        txn.set_root(0, root).unwrap();
+                        if i % 5 == 3 {
+                            // This one is rolled back, without affecting the others.
+                            Err(Error::NotEnoughSpace)
+                        } else if i % 5 == 4 {
+                            // So is this one, and the panic reaches its own thread only.
+                            panic!("closure {}", i)
+                        } else {
+                            Ok(i)
+                        }
+                    })
+                })
+            }).collect();
+            threads.into_iter().map(|t| t.join()).collect()
+        });
+        let txn = env.txn_begin().unwrap();
+        let root = txn.root(0).unwrap().unwrap();
+        for (i, result) in results.into_iter().enumerate() {
+            let key = format!("key {}", i);
+            if i % 5 == 3 {
+                assert!(result.unwrap().is_err());
+                assert!(txn.get(&root, key.as_bytes(), None).is_none())
+            } else if i % 5 == 4 {
+                assert!(result.is_err());
+                assert!(txn.get(&root, key.as_bytes(), None).is_none())
+            } else {
+                assert!(result.unwrap().unwrap() == i);
+                assert!(txn.get(&root, key.as_bytes(), None).and_then(|mut x| x.next()) == Some(b"value"))
+            }
+        }
+    }
+
+    #[test]
+    fn disjoint_roots() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use rand::Rng;
+        use std;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 5000).unwrap();
+        let values:Vec<Vec<(String,String)>> = std::thread::scope(|s| {
+            let threads:Vec<_> = (0..4).map(|n_root| {
+                let env = &env;
+                s.spawn(move || {
+                    let mut rng = rand::thread_rng();
+                    let mut values = Vec::new();
+                    for i in 0..50 {
+                        let mut txn = env.mut_txn_begin_roots(&[n_root]).unwrap();
+                        let mut db = txn.root(n_root).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+                        let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+                        let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+                        txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                        txn.set_root(n_root, db).unwrap();
+                        if i % 7 != 3 {
+                            txn.commit().unwrap();
+                            values.push((k,v))
+                        }
+                    }
+                    values
+                })
+            }).collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        {
+            // Overlapping roots conflict.
+            let _txn = env.mut_txn_begin_roots(&[0, 1]).unwrap();
+            assert!(match env.mut_txn_begin_roots(&[1]) { Err(Error::Conflict) => true, _ => false });
+            let mut txn = env.mut_txn_begin_roots(&[2]).unwrap();
+            let db = txn.create_db().unwrap();
+            txn.set_root(3, db).unwrap();
+            assert!(match txn.commit() { Err(Error::Conflict) => true, _ => false });
+        }
+        // Normal transactions still work after that.
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.root(0).unwrap().unwrap();
+        txn.put(&mut rand::thread_rng(), &mut db, b"key", b"value").unwrap();
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.txn_begin().unwrap();
+        let dbs:Vec<_> = (0..4).map(|n_root| txn.root(n_root).unwrap().unwrap()).collect();
+        for (n_root, values) in values.iter().enumerate() {
+            for &(ref k, ref v) in values.iter() {
+                assert!(txn.get(&dbs[n_root], k.as_bytes(), Some(v.as_bytes())).is_some())
+            }
+        }
+        let dbs:Vec<_> = dbs.iter().collect();
+        check_memory(&env, &txn, &dbs, false);
+    }
+
+    #[test]
+    fn child_abort() {
+        child_abort_(false)
+    }
+
+    #[test]
+    fn child_abort_disjoint() {
+        child_abort_(true)
+    }
+
+    // Aborted children (and grandchildren) must not leak any page,
+    // including the pages they took from the list of free pages.
+    fn child_abort_(disjoint:bool) -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use rand::Rng;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut values = Vec::new();
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.create_db().unwrap();
+            for _ in 0..200 {
+                let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+                let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+                txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                values.push((k,v))
+            }
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        {
+            // Fill the list of free pages.
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.root(0).unwrap().unwrap();
+            for (k, v) in values.drain(100..) {
+                txn.del(&mut rng, &mut db, k.as_bytes(), Some(v.as_bytes())).unwrap();
+            }
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        assert!(env.statistics().unwrap().free_pages.len() > 0);
+        let mut txn = if disjoint { env.mut_txn_begin_roots(&[0]).unwrap() } else { env.mut_txn_begin().unwrap() };
+        let mut db = txn.root(0).unwrap().unwrap();
+        for i in 0..3 {
+            let mut child = txn.mut_txn_begin().unwrap();
+            let mut child_db = unsafe { db.clone() };
+            // Values of the same size as the others: mixing sizes
+            // triggers unrelated bugs in page merges.
+            for _ in 0..100 {
+                let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+                let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+                child.put(&mut rng, &mut child_db, k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            for &(ref k, ref v) in values.iter().take(50) {
+                child.del(&mut rng, &mut child_db, k.as_bytes(), Some(v.as_bytes())).unwrap();
+            }
+            {
+                let mut grandchild = child.mut_txn_begin().unwrap();
+                for _ in 0..50 {
+                    let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+                    let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+                    grandchild.put(&mut rng, &mut child_db, k.as_bytes(), v.as_bytes()).unwrap();
+                }
+            }
+            // Abort the child.
+            std::mem::drop(child);
+            if i == 1 {
+                // Between aborted children, commit one to the parent.
+                let mut child = txn.mut_txn_begin().unwrap();
+                let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+                let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+                child.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                child.commit().unwrap();
+                values.push((k,v))
+            }
+        }
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        for &(ref k, ref v) in values.iter() {
+            assert!(txn.get(&db, k.as_bytes(), Some(v.as_bytes())).is_some())
+        }
+        assert_eq!(txn.iter(&db, b"", None).count(), values.len());
+        check_memory(&env, &txn, &[&db], false);
+    }
+
+    #[test]
+    fn poison() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use rand::Rng;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        let mut values = Vec::new();
+        // Make `fork_db` fail on its first allocation, which poisons the transaction.
+        fn poison<T>(env:&Env, txn:&mut MutTxn<T>, db:&mut Db) {
+            let mut rng = rand::thread_rng();
+            env.env.fail_alloc.store(1, std::sync::atomic::Ordering::SeqCst);
+            assert!(match txn.fork_db(&mut rng, db) { Err(Error::NotEnoughSpace) => true, _ => false });
+            assert!(match txn.put(&mut rng, db, b"key", b"value") { Err(Error::Poison) => true, _ => false });
+            assert!(match txn.del(&mut rng, db, b"key", None) { Err(Error::Poison) => true, _ => false });
+            assert!(match txn.create_db() { Err(Error::Poison) => true, _ => false });
+        }
+        let mut put = |txn:&mut MutTxn<()>, db:&mut Db| {
+            let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+            let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+            txn.put(&mut rand::thread_rng(), db, k.as_bytes(), v.as_bytes()).unwrap();
+            values.push((k,v))
+        };
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.create_db().unwrap();
+            put(&mut txn, &mut db);
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        {
+            // A poisoned child cannot be committed, but its parent can.
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.root(0).unwrap().unwrap();
+            {
+                let mut child = txn.mut_txn_begin().unwrap();
+                let mut child_db = unsafe { db.clone() };
+                poison(&env, &mut child, &mut child_db);
+                assert!(match child.commit() { Err(Error::Poison) => true, _ => false });
+            }
+            put(&mut txn, &mut db);
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        {
+            // Rolling back to a savepoint taken before the failure clears the poison.
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.root(0).unwrap().unwrap();
+            let sp = txn.savepoint();
+            poison(&env, &mut txn, &mut db);
+            txn.rollback_to(sp).unwrap();
+            put(&mut txn, &mut db);
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.root(0).unwrap().unwrap();
+            poison(&env, &mut txn, &mut db);
+            txn.set_root(0, db).unwrap();
+            assert!(match txn.commit() { Err(Error::Poison) => true, _ => false });
+        }
+        let txn = env.txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        assert_eq!(txn.iter(&db, b"", None).count(), values.len());
+        for &(ref k, ref v) in values.iter() {
+            assert!(txn.get(&db, k.as_bytes(), Some(v.as_bytes())).is_some())
+        }
+        check_memory(&env, &txn, &[&db], false);
+    }
+
+    fn contents<T:Transaction>(txn:&T, db:&Db) -> Vec<(Vec<u8>, Vec<u8>)> {
+        txn.iter(db, b"", None).map(|(k, v)| {
+            let mut value = Vec::new();
+            for chunk in v {
+                value.extend_from_slice(chunk)
+            }
+            (k.to_vec(), value)
+        }).collect()
+    }
+
+    // Make every allocation of each operation fail in turn, and check
+    // that the transaction is left unchanged each time.
+    #[test]
+    fn atomic_operations() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use rand::Rng;
+        use std::sync::atomic::Ordering;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 2000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut keys = Vec::new();
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.create_db().unwrap();
+            for _ in 0..100 {
+                let k: String = rand::thread_rng().gen_ascii_chars().take(100).collect();
+                let v: String = rand::thread_rng().gen_ascii_chars().take(800).collect();
+                txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                keys.push(k)
+            }
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.root(0).unwrap().unwrap();
+        // Share pages with a fork, to go through the reference counts.
+        let fork = txn.fork_db(&mut rng, &db).unwrap();
+        let fork_contents = contents(&txn, &fork);
+        let mut failures = 0;
+        for i in 0..60 {
+            let k: String = rand::thread_rng().gen_ascii_chars().take(100).collect();
+            let v: String = rand::thread_rng().gen_ascii_chars().take(800).collect();
+            let old = rng.gen_range(0, keys.len());
+            for n in 1.. {
+                let before = contents(&txn, &db);
+                let root = db.root;
+                env.env.fail_alloc.store(n, Ordering::SeqCst);
+                let result = match i % 3 {
+                    0 => txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).map(|_| ()),
+                    1 => txn.del(&mut rng, &mut db, keys[old].as_bytes(), None).map(|_| ()),
+                    _ => txn.replace(&mut rng, &mut db, keys[old].as_bytes(), v.as_bytes()),
+                };
+                match result {
+                    Ok(()) => {
+                        env.env.fail_alloc.store(0, Ordering::SeqCst);
+                        break
+                    }
+                    Err(Error::NotEnoughSpace) => {
+                        failures += 1;
+                        assert_eq!(db.root, root);
+                        assert!(contents(&txn, &db) == before);
+                        assert!(contents(&txn, &fork) == fork_contents);
+                    }
+                    Err(e) => panic!("{:?}", e)
+                }
+            }
+            match i % 3 {
+                0 => keys.push(k),
+                1 => { keys.swap_remove(old); },
+                _ => {}
+            }
+        }
+        // Operations writing only pages allocated by the previous
+        // ones are done in place, and cannot fail.
+        assert!(failures > 30);
+        txn.set_root(0, db).unwrap();
+        txn.set_root(1, fork).unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        let fork = txn.root(1).unwrap().unwrap();
+        assert_eq!(txn.iter(&db, b"", None).count(), keys.len());
+        for k in keys.iter() {
+            assert!(txn.get(&db, k.as_bytes(), None).is_some())
+        }
+        assert!(contents(&txn, &fork) == fork_contents);
+        let rc_db = txn.rc().unwrap();
+        check_memory(&env, &txn, &[&db, &fork, &rc_db], false);
+    }
+
+    // Pages allocated by previous operations of the transaction are
+    // written in place, and restored when an operation fails.
+    #[test]
+    fn atomic_in_place() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use std::sync::atomic::Ordering;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 2000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db_with_options(DbOptions { counted: true, ..DbOptions::default() }).unwrap();
+        let value = [b'v'; 100];
+        let mut failures = 0;
+        for i in 0..1000 {
+            let k = format!("{:08}", (i * 7919) % 1000);
+            for n in 1.. {
+                let before = contents(&txn, &db);
+                env.env.fail_alloc.store(n, Ordering::SeqCst);
+                match txn.put(&mut rng, &mut db, k.as_bytes(), &value) {
+                    Ok(_) => {
+                        env.env.fail_alloc.store(0, Ordering::SeqCst);
+                        break
+                    }
+                    Err(Error::NotEnoughSpace) => {
+                        failures += 1;
+                        assert!(contents(&txn, &db) == before);
+                        assert_eq!(txn.len(&db), before.len() as u64);
+                    }
+                    Err(e) => panic!("{:?}", e)
+                }
+            }
+        }
+        assert!(failures > 0);
+        assert_eq!(txn.len(&db), 1000);
+        assert_eq!(txn.stats().copied_pages, 0);
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+        let txn = env.txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        assert_eq!(contents(&txn, &db).len(), 1000);
+        check_memory(&env, &txn, &[&db], false);
+    }
+
+    // Dropping a fork must record the new root of the reference
+    // counts, which is copied on write like any other page.
+    #[test]
+    fn fork_drop() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        let mut rng = rand::thread_rng();
+        for i in 0..3 {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+            for j in 0..200 {
+                txn.put_u64(&mut rng, &mut db, i * 1000 + j, j).unwrap();
+            }
+            let forked = txn.fork_db(&mut rng, &db).unwrap();
+            txn.drop(&mut rng, forked).unwrap();
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        let txn = env.txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        assert_eq!(txn.iter(&db, b"", None).count(), 600);
+        check_memory(&env, &txn, &[&db, &txn.rc().unwrap()], true);
+    }
+
+    #[test]
+    fn invalid_input() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        txn.put(&mut rng, &mut db, b"key", b"value").unwrap();
+        let large = vec![0; MAX_KEY_SIZE];
+        assert!(match txn.put(&mut rng, &mut db, &large, b"value") { Err(Error::KeyTooLarge) => true, _ => false });
+        assert!(match txn.replace(&mut rng, &mut db, &large, b"value") { Err(Error::KeyTooLarge) => true, _ => false });
+        assert!(match txn.del(&mut rng, &mut db, &large, None) { Err(Error::KeyTooLarge) => true, _ => false });
+        let mut invalid = [
+            Db { root: 0, root_num: -1, unique: false },
+            Db { root: 4097, root_num: -1, unique: false },
+            Db { root: 100 * 4096, root_num: -1, unique: false },
+        ];
+        for db in invalid.iter_mut() {
+            let root = db.root;
+            assert!(match txn.put(&mut rng, db, b"key", b"value") { Err(Error::InvalidPage(p)) => p == root, _ => false });
+            assert!(match txn.del(&mut rng, db, b"key", None) { Err(Error::InvalidPage(p)) => p == root, _ => false });
+            assert!(match txn.fork_db(&mut rng, db) { Err(Error::InvalidPage(p)) => p == root, _ => false });
+            assert!(txn.get(db, b"key", None).is_none());
+            assert!(txn.iter(db, b"", None).next().is_none());
+            assert!(txn.open_db(db, b"key").is_none());
+        }
+        // The transaction is still usable.
+        txn.put(&mut rng, &mut db, b"other key", b"value").unwrap();
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+        let txn = env.txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        assert_eq!(txn.iter(&db, b"", None).count(), 2);
+    }
+
+    // With a fixed seed, transactions draw the same levels every time
+    // the environment is opened.
+    #[test]
+    fn owned_rng() -> ()
+    {
+        extern crate tempdir;
+        use rand::Rng;
+        let mut draws = Vec::new();
+        for _ in 0..2 {
+            let dir = tempdir::TempDir::new("pijul").unwrap();
+            let env = Env::new_with_options(dir.path(), EnvOptions { seed: Some(17), ..EnvOptions::new(100) }).unwrap();
+            let mut run = Vec::new();
+            for i in 0..3 {
+                let mut txn = env.mut_txn_begin().unwrap();
+                let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+                {
+                    let mut child = txn.mut_txn_begin().unwrap();
+                    run.push(child.rng.gen::<u64>());
+                }
+                run.push(txn.rng.gen::<u64>());
+                for j in 0..200u64 {
+                    txn.put_u64_auto(&mut db, i * 1000 + j, j).unwrap();
+                }
+                for j in 0..100u64 {
+                    txn.del_u64_auto(&mut db, i * 1000 + 2 * j).unwrap();
+                }
+                txn.replace_u64_auto(&mut db, i * 1000 + 1, 0).unwrap();
+                let forked = txn.fork_db_auto(&db).unwrap();
+                txn.drop_auto(forked).unwrap();
+                txn.set_root(0, db).unwrap();
+                txn.commit().unwrap();
+            }
+            let txn = env.txn_begin().unwrap();
+            let db = txn.root(0).unwrap().unwrap();
+            assert_eq!(txn.iter(&db, b"", None).count(), 300);
+            draws.push(run);
+        }
+        assert_eq!(draws[0], draws[1]);
+        // Successive transactions, and children, draw different numbers.
+        let mut run = draws[0].clone();
+        run.sort();
+        run.dedup();
+        assert_eq!(run.len(), draws[0].len());
+    }
+
+    // In deterministic mode, the same transactions yield the same
+    // file, even with random generators.
+    #[test]
+    fn deterministic_layout() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        fn run(path:&std::path::Path) -> Vec<u8> {
+            let env = Env::new_with_options(path, EnvOptions { deterministic: true, ..EnvOptions::new(2000) }).unwrap();
+            let mut rng = rand::thread_rng();
+            for i in 0..4 {
+                let mut txn = env.mut_txn_begin().unwrap();
+                let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+                for j in 0..300 {
+                    let k = format!("{:08}", (j * 7919 + i) % 1000);
+                    txn.put(&mut rng, &mut db, k.as_bytes(), &[j as u8; 300]).unwrap();
+                }
+                for j in 0..100 {
+                    let k = format!("{:08}", (j * 31) % 1000);
+                    txn.del(&mut rng, &mut db, k.as_bytes(), None).unwrap();
+                }
+                let forked = txn.fork_db(&mut rng, &db).unwrap();
+                txn.drop(&mut rng, forked).unwrap();
+                {
+                    let mut child = txn.mut_txn_begin().unwrap();
+                    let mut child_db = unsafe { db.clone() };
+                    child.put(&mut rng, &mut child_db, b"child", &[1; 300]).unwrap();
+                    if i % 2 == 0 {
+                        child.commit().unwrap();
+                        db = child_db
+                    }
+                }
+                let before = unsafe { db.clone() };
+                let sp = txn.savepoint();
+                txn.put(&mut rng, &mut db, b"savepoint", &[2; 300]).unwrap();
+                txn.rollback_to(sp).unwrap();
+                txn.set_root(0, before).unwrap();
+                txn.commit().unwrap();
+            }
+            let mut contents = Vec::new();
+            std::fs::File::open(path.join("db")).unwrap().read_to_end(&mut contents).unwrap();
+            contents
+        }
+        use std::io::Read;
+        let dir_a = tempdir::TempDir::new("pijul").unwrap();
+        let dir_b = tempdir::TempDir::new("pijul").unwrap();
+        assert!(run(dir_a.path()) == run(dir_b.path()));
+    }
+
+    #[test]
+    fn txn_stats() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        assert_eq!(txn.stats(), TxnStatistics { fresh_pages: 1, ..TxnStatistics::default() });
+        let mut values = txn.create_db().unwrap();
+        // 10000 bytes span three pages of PAGE_SIZE - 8 bytes.
+        txn.put(&mut rng, &mut values, b"large", &[0; 10000]).unwrap();
+        assert_eq!(txn.stats().value_pages, 3);
+        for i in 0..200 {
+            txn.put_u64(&mut rng, &mut db, i, i).unwrap();
+        }
+        txn.set_root(0, db).unwrap();
+        txn.set_root(1, values).unwrap();
+        txn.commit().unwrap();
+
+        // Pages of the previous transaction are copied before being changed.
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.root(0).unwrap().unwrap();
+        txn.put_u64(&mut rng, &mut db, 1000, 1000).unwrap();
+        let stats = txn.stats();
+        assert!(stats.copied_pages >= 1 && stats.freed_pages >= 1);
+        assert_eq!(stats.rc_updates, 0);
+        let forked = txn.fork_db(&mut rng, &db).unwrap();
+        // Forking only counts the root once.
+        assert_eq!(txn.stats().rc_updates, 1);
+        txn.drop(&mut rng, forked).unwrap();
+        // At least one increment and one decrement. Once the
+        // reference counts exist, copying a page also updates them.
+        assert!(txn.stats().rc_updates >= 2);
+        // Children add their counters to their parent when committed.
+        let before = txn.stats();
+        let child_stats = {
+            let mut child = txn.mut_txn_begin().unwrap();
+            for i in 0..100 {
+                child.put_u64(&mut rng, &mut db, 2000 + i, i).unwrap();
+            }
+            let child_stats = child.stats();
+            child.commit().unwrap();
+            child_stats
+        };
+        assert!(child_stats.fresh_pages + child_stats.free_list_pages + child_stats.reused_clean_pages > 0);
+        assert_eq!(txn.stats().copied_pages, before.copied_pages + child_stats.copied_pages);
+        assert_eq!(txn.stats().fresh_pages, before.fresh_pages + child_stats.fresh_pages);
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+
+        // The pages freed by the last commit are allocated again.
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.root(0).unwrap().unwrap();
+        txn.put_u64(&mut rng, &mut db, 3000, 3000).unwrap();
+        assert!(txn.stats().free_list_pages >= 1);
+    }
+
+    // Import bindings in chunks, committing each time the limit is
+    // reached.
+    #[test]
+    fn page_limit() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let value = [0; 500];
+        let mut i = 0;
+        let mut commits = 0;
+        while i < 500 {
+            let mut txn = env.mut_txn_begin().unwrap();
+            txn.set_page_limit(Some(20));
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+            while i < 500 {
+                let k = format!("{:08}", i);
+                match txn.put(&mut rng, &mut db, k.as_bytes(), &value) {
+                    Ok(_) => i += 1,
+                    Err(Error::TxnTooLarge) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+                assert!(txn.dirty_pages() <= 20);
+            }
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+            commits += 1;
+        }
+        assert!(commits > 1);
+        {
+            let txn = env.txn_begin().unwrap();
+            let db = txn.root(0).unwrap().unwrap();
+            assert_eq!(txn.iter(&db, b"", None).count(), 500);
+        }
+
+        // A failed commit keeps the limit (the commit of the inner
+        // transaction doesn't consume it).
+        {
+            use transaction::Commit;
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.root(0).unwrap().unwrap();
+            let held = txn.dirty_pages();
+            txn.set_page_limit(Some(held));
+            env.env.fail_alloc.store(1, std::sync::atomic::Ordering::SeqCst);
+            assert!(match txn.txn.commit() { Err(Error::NotEnoughSpace) => true, _ => false });
+            assert!(match txn.put(&mut rng, &mut db, b"key", &value) { Err(Error::TxnTooLarge) => true, _ => false });
+        }
+
+        // Children count the pages of their parent.
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.root(0).unwrap().unwrap();
+        txn.put(&mut rng, &mut db, b"parent", &value).unwrap();
+        let held = txn.dirty_pages();
+        assert!(held > 0);
+        txn.set_page_limit(Some(held + 5));
+        let child_pages = {
+            let mut child = txn.mut_txn_begin().unwrap();
+            assert_eq!(child.dirty_pages(), held);
+            let mut child_db = unsafe { db.clone() };
+            let mut n = 0;
+            loop {
+                let k = format!("child{:08}", n);
+                match child.put(&mut rng, &mut child_db, k.as_bytes(), &value) {
+                    Ok(_) => n += 1,
+                    Err(Error::TxnTooLarge) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            assert!(child.dirty_pages() <= held + 5);
+            let pages = child.dirty_pages();
+            child.commit().unwrap();
+            db = child_db;
+            pages
+        };
+        assert_eq!(txn.dirty_pages(), child_pages);
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn iter_rev() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut bindings = Vec::new();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        for i in 0..1000 {
+            // Three values per key, inserted out of order.
+            let k = format!("{:08}", (i * 7919) % 1000);
+            for j in 0..3 {
+                let v = format!("{:040}", (j * 2) % 3);
+                txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                bindings.push((k.clone(), v));
+            }
+        }
+        bindings.sort();
+        let collect = |it:&mut dyn Iterator<Item=(&[u8], txn::Value<_>)>| -> Vec<(String, String)> {
+            it.map(|(k, mut v)| (String::from_utf8(k.to_vec()).unwrap(),
+                                 String::from_utf8(v.next().unwrap().to_vec()).unwrap())).collect()
+        };
+        let all_rev:Vec<_> = bindings.iter().cloned().rev().collect();
+        assert_eq!(collect(&mut txn.iter(&db, b"", None).rev()), all_rev);
+
+        // Starting at a key includes all its values.
+        let k = format!("{:08}", 500);
+        let expected:Vec<_> = all_rev.iter().cloned().filter(|&(ref kk, _)| *kk <= k).collect();
+        assert_eq!(collect(&mut txn.iter_rev(&db, k.as_bytes(), None)), expected);
+
+        // Starting at a value.
+        let v = format!("{:040}", 1);
+        let expected:Vec<_> = all_rev.iter().cloned().filter(|&(ref kk, ref vv)| (kk, vv) <= (&k, &v)).collect();
+        assert_eq!(collect(&mut txn.iter_rev(&db, k.as_bytes(), Some(v.as_bytes()))), expected);
+        assert_eq!(txn.iter_rev(&db, k.as_bytes(), Some(v.as_bytes())).next().unwrap().0, k.as_bytes());
+
+        // Before the first key, and after the last one.
+        assert_eq!(txn.iter_rev(&db, b"", None).count(), 0);
+        assert_eq!(txn.iter(&db, b"a", None).next_back().is_none(), true);
+        assert_eq!(txn.iter_rev(&db, b"a", None).count(), 3000);
+
+        // Both ends stop when they meet.
+        let expected:Vec<_> = bindings.iter().cloned().filter(|&(ref kk, _)| *kk >= k).collect();
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        {
+            let mut it = txn.iter(&db, k.as_bytes(), None);
+            loop {
+                match it.next() {
+                    Some((kk, mut vv)) => front.push((String::from_utf8(kk.to_vec()).unwrap(),
+                                                      String::from_utf8(vv.next().unwrap().to_vec()).unwrap())),
+                    None => break
+                }
+                for _ in 0..2 {
+                    if let Some((kk, mut vv)) = it.next_back() {
+                        back.push((String::from_utf8(kk.to_vec()).unwrap(),
+                                   String::from_utf8(vv.next().unwrap().to_vec()).unwrap()))
+                    }
+                }
+            }
+            assert!(it.next_back().is_none());
+        }
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, expected);
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn iter_range() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use std::ops::Bound::*;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut keys = Vec::new();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        // Even keys only, with two values each.
+        for i in 0..1000 {
+            let k = format!("{:08}", ((i * 7919) % 1000) * 2);
+            for v in &[b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", b"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"] {
+                txn.put(&mut rng, &mut db, k.as_bytes(), *v).unwrap();
+                keys.push(k.clone())
+            }
+        }
+        keys.sort();
+        let ks:Vec<String> = vec![0, 1, 2, 500, 501, 1000, 1998, 1999, 3000].into_iter().map(|i| format!("{:08}", i)).collect();
+        let mut bounds = vec![Unbounded];
+        for k in ks.iter() {
+            bounds.push(Included(k.as_bytes()));
+            bounds.push(Excluded(k.as_bytes()));
+        }
+        let after = |k:&[u8], b:&Bound<&[u8]>| match *b { Included(s) => k >= s, Excluded(s) => k > s, Unbounded => true };
+        let before = |k:&[u8], b:&Bound<&[u8]>| match *b { Included(e) => k <= e, Excluded(e) => k < e, Unbounded => true };
+        for start in bounds.iter() {
+            for end in bounds.iter() {
+                let expected:Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).filter(|k| after(k, start) && before(k, end)).collect();
+                let got:Vec<&[u8]> = txn.iter_range(&db, *start, *end).map(|(k, _)| k).collect();
+                assert_eq!(got, expected);
+                let mut got:Vec<&[u8]> = txn.iter_range(&db, *start, *end).rev().map(|(k, _)| k).collect();
+                got.reverse();
+                assert_eq!(got, expected);
+            }
+        }
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn iter_prefix() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        let mut bindings = Vec::new();
+        for repo in &["pijul", "sanakirja", "sanakirja2"] {
+            for branch in 0..20 {
+                for hash in 0..10 {
+                    let k = format!("{}/{:02}/{:04}", repo, branch, hash).into_bytes();
+                    for v in &[b"aaaaaaaaaaaaaaaaaaaa", b"bbbbbbbbbbbbbbbbbbbb"] {
+                        txn.put(&mut rng, &mut db, &k, *v).unwrap();
+                        bindings.push((k.clone(), v.to_vec()))
+                    }
+                }
+            }
+        }
+        // Prefixes ending with 0xff have no upper bound of the same length.
+        for k in &[vec![b'x', 0xff, 0xff], vec![b'x', 0xff, 0xff, 0]] {
+            txn.put(&mut rng, &mut db, k, b"aaaaaaaaaaaaaaaaaaaa").unwrap();
+            bindings.push((k.clone(), b"aaaaaaaaaaaaaaaaaaaa".to_vec()))
+        }
+        bindings.sort();
+        let prefixes:&[&[u8]] = &[b"", b"pijul/", b"sanakirja/", b"sanakirja", b"sanakirja/07/", b"sanakirja/07/0003",
+                                  b"pijul/99", b"q", &[b'x', 0xff], &[b'x', 0xff, 0xff], &[0xff]];
+        for prefix in prefixes {
+            let expected:Vec<_> = bindings.iter().filter(|&&(ref k, _)| k.starts_with(prefix)).cloned().collect();
+            let got:Vec<_> = txn.iter_prefix(&db, prefix).map(|(k, mut v)| (k.to_vec(), v.next().unwrap().to_vec())).collect();
+            assert_eq!(got, expected);
+        }
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn cursor() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        let mut bindings = Vec::new();
+        for i in 0..1000 {
+            let k = format!("{:08}", (i * 7919) % 1000);
+            for v in &["aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"] {
+                txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                bindings.push((k.clone(), v.to_string()))
+            }
+        }
+        bindings.sort();
+        let owned = |b:Option<(&[u8], txn::Value<_>)>| {
+            b.map(|(k, mut v)| (String::from_utf8(k.to_vec()).unwrap(),
+                                String::from_utf8(v.next().unwrap().to_vec()).unwrap()))
+        };
+        {
+            let mut cursor = txn.cursor(&mut db).unwrap();
+            assert!(cursor.current().is_none());
+            for b in bindings.iter() {
+                assert_eq!(owned(cursor.next()).as_ref(), Some(b));
+            }
+            assert!(cursor.next().is_none());
+            for b in bindings.iter().rev() {
+                assert_eq!(owned(cursor.prev()).as_ref(), Some(b));
+            }
+            assert!(cursor.prev().is_none());
+            assert_eq!(owned(cursor.seek(b"00000500", None)), Some(bindings[1000].clone()));
+            assert_eq!(owned(cursor.seek(b"00000500", Some(b"b"))), Some(bindings[1001].clone()));
+            assert_eq!(owned(cursor.prev()), Some(bindings[1000].clone()));
+            assert!(cursor.seek(b"a", None).is_none());
+        }
+
+        // Walk forward, deleting every "a" value and replacing every
+        // third "b" value, then check both directions.
+        let mut expected = Vec::new();
+        {
+            let mut cursor = txn.cursor(&mut db).unwrap();
+            let mut current = owned(cursor.next());
+            let mut i = 0;
+            while let Some((k, v)) = current {
+                if v.starts_with("a") {
+                    assert!(cursor.delete_current(&mut rng).unwrap());
+                    current = owned(cursor.current());
+                } else {
+                    if i % 3 == 0 {
+                        assert!(cursor.replace_current(&mut rng, b"cccccccccccccccccccccccccccccc").unwrap());
+                        assert_eq!(owned(cursor.current()), Some((k.clone(), "cccccccccccccccccccccccccccccc".to_string())));
+                        expected.push((k, "cccccccccccccccccccccccccccccc".to_string()));
+                    } else {
+                        expected.push((k, v));
+                    }
+                    i += 1;
+                    current = owned(cursor.next());
+                }
+            }
+            for b in expected.iter().rev() {
+                assert_eq!(owned(cursor.prev()).as_ref(), Some(b));
+            }
+            assert!(cursor.prev().is_none());
+            // Delete the last 100 bindings, from the end: after a
+            // deletion, the cursor is past the end again.
+            let mut n = 0;
+            while cursor.prev().is_some() {
+                assert!(cursor.delete_current(&mut rng).unwrap());
+                assert!(cursor.current().is_none());
+                n += 1;
+                if n == 100 {
+                    break
+                }
+            }
+        }
+        let got:Vec<_> = txn.iter(&db, b"", None).map(|x| owned(Some(x)).unwrap()).collect();
+        assert_eq!(&got[..], &expected[..expected.len() - 100]);
+
+        // Replacing a binding that is not the first of its key, or
+        // one that moves past the next value of its key.
+        let mut db = txn.create_db().unwrap();
+        let (a, b, c, d) = ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+                            "cccccccccccccccccccccccccccccc", "dddddddddddddddddddddddddddddd");
+        txn.put(&mut rng, &mut db, b"k", a.as_bytes()).unwrap();
+        txn.put(&mut rng, &mut db, b"k", b.as_bytes()).unwrap();
+        {
+            let mut cursor = txn.cursor(&mut db).unwrap();
+            cursor.seek(b"k", Some(b.as_bytes()));
+            assert!(cursor.replace_current_auto(c.as_bytes()).unwrap());
+            assert_eq!(owned(cursor.current()), Some(("k".to_string(), c.to_string())));
+            cursor.seek(b"k", None);
+            assert!(cursor.replace_current_auto(d.as_bytes()).unwrap());
+            assert_eq!(owned(cursor.current()), Some(("k".to_string(), d.to_string())));
+            assert!(cursor.delete_current_auto().unwrap());
+            assert!(cursor.current().is_none());
+        }
+        let got:Vec<_> = txn.iter(&db, b"", None).map(|x| owned(Some(x)).unwrap()).collect();
+        assert_eq!(got, vec![("k".to_string(), c.to_string())]);
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn cursor_fork() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let (a, b) = (b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", b"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.create_db_with_options(DbOptions { counted: true, ..DbOptions::default() }).unwrap();
+            for i in 0..1000 {
+                txn.put(&mut rng, &mut db, format!("{:08}", i).as_bytes(), a).unwrap();
+            }
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        // The pages of the fork are shared and clean, and the
+        // reference counts are updated in the middle of each change.
+        let mut txn = env.mut_txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        let mut fork = txn.fork_db(&mut rng, &db).unwrap();
+        {
+            let mut cursor = txn.cursor(&mut fork).unwrap();
+            let mut i = 0;
+            while let Some((k, _)) = cursor.next() {
+                assert_eq!(k, format!("{:08}", i).as_bytes());
+                if i % 3 == 0 {
+                    assert!(cursor.replace_current(&mut rng, b).unwrap());
+                } else {
+                    assert!(cursor.delete_current(&mut rng).unwrap());
+                    cursor.prev();
+                }
+                i += 1
+            }
+            assert_eq!(i, 1000);
+        }
+        let expected:Vec<_> = (0..1000).filter(|i| i % 3 == 0).map(|i| format!("{:08}", i)).collect();
+        let got:Vec<_> = txn.iter(&fork, b"", None).map(|(k, mut v)| {
+            assert_eq!(v.next().unwrap(), &b[..]);
+            String::from_utf8(k.to_vec()).unwrap()
+        }).collect();
+        assert_eq!(got, expected);
+        assert_eq!(txn.len(&fork), expected.len() as u64);
+        assert_eq!(txn.rank(&fork, b"00000300", None), 100);
+        assert_eq!(txn.len(&db), 1000);
+        assert!(txn.iter(&db, b"", None).all(|(_, mut v)| v.next().unwrap() == &a[..]));
+    }
+
+    #[test]
+    fn iter_from_token() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let value = b"vvvvvvvvvvvvvvvvvvvvvvvvvvvvvv";
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.create_db().unwrap();
+            for i in 0..1000 {
+                txn.put(&mut rng, &mut db, format!("{:08}", i * 2).as_bytes(), value).unwrap();
+            }
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        assert!(IterToken::from_bytes(&[]).is_none());
+        assert!(IterToken::from_bytes(&[3, 0, b'a']).is_none());
+
+        let mut token:Option<Vec<u8>> = None;
+        let mut seen = Vec::new();
+        let mut expected:Vec<u64> = (0..1000).map(|i| i * 2).collect();
+        loop {
+            let page:Vec<u64> = {
+                let txn = env.txn_begin().unwrap();
+                let db = txn.root(0).unwrap().unwrap();
+                let mut iter = if let Some(ref token) = token {
+                    txn.iter_from_token(&db, &IterToken::from_bytes(token).unwrap())
+                } else {
+                    txn.iter(&db, b"", None)
+                };
+                let page = iter.by_ref().take(50).map(|(k, _)| std::str::from_utf8(k).unwrap().parse().unwrap()).collect();
+                token = iter.position().map(|t| t.as_bytes().to_vec());
+                page
+            };
+            seen.extend(page.iter().cloned());
+            if page.len() < 50 {
+                break
+            }
+            // Delete the last binding we've seen, and add bindings
+            // just before and just after it.
+            let last = *page.last().unwrap();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut db = txn.root(0).unwrap().unwrap();
+            txn.del(&mut rng, &mut db, format!("{:08}", last).as_bytes(), None).unwrap();
+            if last > 0 {
+                txn.put(&mut rng, &mut db, format!("{:08}", last - 1).as_bytes(), value).unwrap();
+            }
+            txn.put(&mut rng, &mut db, format!("{:08}", last + 1).as_bytes(), value).unwrap();
+            expected.push(last + 1);
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn get_all() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        // Key i has i % 7 values.
+        for i in 0..500 {
+            let k = format!("{:08}", i);
+            for j in 0..(i % 7) {
+                let v = format!("{:030}", j);
+                txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+            }
+        }
+        for i in 0..500 {
+            let k = format!("{:08}", i);
+            let expected:Vec<String> = (0..(i % 7)).map(|j| format!("{:030}", j)).collect();
+            let got:Vec<String> = txn.get_all(&db, k.as_bytes())
+                .map(|v| String::from_utf8(v.collect::<Vec<_>>().concat()).unwrap())
+                .collect();
+            assert_eq!(got, expected);
+            let got:Vec<String> = txn.get_all(&db, k.as_bytes()).rev()
+                .map(|v| String::from_utf8(v.collect::<Vec<_>>().concat()).unwrap())
+                .collect();
+            assert_eq!(got, expected.into_iter().rev().collect::<Vec<_>>());
+            assert_eq!(txn.count_values(&db, k.as_bytes()), i % 7);
+        }
+        assert!(txn.get_all(&db, b"0000000").next().is_none());
+        assert!(txn.get_all(&db, b"000005000").next().is_none());
+        assert_eq!(txn.count_values(&db, b"000005000"), 0);
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn unique_db() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_unique_db().unwrap();
+        let mut catalogue = txn.create_db().unwrap();
+        let mut expected = std::collections::HashMap::new();
+        for round in 0..4 {
+            for i in 0..1000 {
+                let k = format!("{:08}", (i * 7919) % 1000);
+                let v = format!("{:030}", round * 1000 + i);
+                assert!(txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap());
+                expected.insert(k, v);
+            }
+            assert_eq!(txn.len(&db), 1000);
+        }
+        // Putting the same binding again doesn't change anything.
+        assert!(!txn.put(&mut rng, &mut db, b"00000000", expected["00000000"].as_bytes()).unwrap());
+        txn.replace(&mut rng, &mut db, b"00000001", b"replaced").unwrap();
+        expected.insert("00000001".to_string(), "replaced".to_string());
+        assert!(txn.del(&mut rng, &mut db, b"00000002", None).unwrap());
+        expected.remove("00000002");
+        assert_eq!(txn.len(&db), 999);
+        txn.put_db(&mut rng, &mut catalogue, b"db", db).unwrap();
+        txn.set_root(0, catalogue).unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.txn_begin().unwrap();
+        let catalogue = txn.root(0).unwrap().unwrap();
+        assert!(!catalogue.is_unique());
+        let db = txn.open_db(&catalogue, b"db").unwrap();
+        assert!(db.is_unique());
+        let mut n = 0;
+        for (k, v) in txn.iter(&db, b"", None) {
+            let v:Vec<u8> = v.collect::<Vec<_>>().concat();
+            assert_eq!(v, expected[std::str::from_utf8(k).unwrap()].as_bytes());
+            assert_eq!(txn.count_values(&db, k), 1);
+            n += 1
+        }
+        assert_eq!(n, 999);
+    }
+
+    #[test]
+    fn replace_in_place() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        for i in 0..2000 {
+            let k = format!("{:08}", (i * 7919) % 2000);
+            txn.put(&mut rng, &mut db, k.as_bytes(), b"0000000000").unwrap();
+        }
+        for v in &[b"aaaaaaaaaa", b"cccccccccc", b"eeeeeeeeee"] {
+            txn.put(&mut rng, &mut db, b"00001000", *v).unwrap();
+        }
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.root(0).unwrap().unwrap();
+        for i in 0..2000 {
+            let k = format!("{:08}", i);
+            txn.replace(&mut rng, &mut db, k.as_bytes(), b"1111111111").unwrap();
+        }
+        // Values of the same size are rewritten in their page, the
+        // only pages allocated are copies of the existing ones.
+        let stats = txn.stats();
+        assert_eq!(stats.fresh_pages + stats.free_list_pages + stats.reused_clean_pages, stats.copied_pages);
+        assert_eq!(txn.len(&db), 2003);
+        // The smallest binding is replaced ("1111111111", and then
+        // "aaaaaaaaaa"), and the bindings of a key stay in order.
+        txn.replace(&mut rng, &mut db, b"00001000", b"bbbbbbbbbb").unwrap();
+        txn.replace(&mut rng, &mut db, b"00001000", b"dddddddddd").unwrap();
+        let values:Vec<Vec<u8>> = txn.get_all(&db, b"00001000").map(|v| v.collect::<Vec<_>>().concat()).collect();
+        assert_eq!(values, vec![b"bbbbbbbbbb".to_vec(), b"cccccccccc".to_vec(), b"dddddddddd".to_vec(), b"eeeeeeeeee".to_vec()]);
+        txn.replace(&mut rng, &mut db, b"00005000", b"2222222222").unwrap();
+        assert_eq!(txn.len(&db), 2004);
+        for (k, v) in txn.iter(&db, b"", None).take(1000) {
+            assert_eq!(v.collect::<Vec<_>>().concat(), b"1111111111");
+            assert!(k < b"00001000");
+        }
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn replace_order() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use rand::Rng;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        // Enough values of one key to fill several pages, so that the
+        // next value is either on the same page, below it, or above.
+        let mut expected = std::collections::BTreeSet::new();
+        for _ in 0..600 {
+            let v = format!("{:010}", rng.gen_range(0, 1000000));
+            txn.put(&mut rng, &mut db, b"k", v.as_bytes()).unwrap();
+            expected.insert(v);
+        }
+        txn.put(&mut rng, &mut db, b"j", b"0000000000").unwrap();
+        txn.put(&mut rng, &mut db, b"l", b"0000000000").unwrap();
+        for i in 0..600 {
+            let v = format!("{:010}", rng.gen_range(0, 1000000));
+            let smallest = expected.iter().next().unwrap().clone();
+            let old = if i % 2 == 0 {
+                txn.upsert(&mut rng, &mut db, b"k", v.as_bytes()).unwrap()
+            } else {
+                assert!(txn.compare_and_swap(&mut rng, &mut db, b"k", smallest.as_bytes(), v.as_bytes()).unwrap());
+                Some(smallest.clone().into_bytes())
+            };
+            assert_eq!(old, Some(smallest.clone().into_bytes()));
+            expected.remove(&smallest);
+            expected.insert(v);
+            let values:Vec<Vec<u8>> = txn.get_all(&db, b"k").map(|v| v.collect::<Vec<_>>().concat()).collect();
+            let expected_values:Vec<Vec<u8>> = expected.iter().map(|v| v.clone().into_bytes()).collect();
+            assert_eq!(values, expected_values);
+        }
+        assert_eq!(txn.len(&db), expected.len() as u64 + 2);
+        txn.set_root(0, db).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn conditional_writes() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db().unwrap();
+        let mut unique = txn.create_unique_db().unwrap();
+        for db in &mut [&mut db, &mut unique] {
+            for i in 0..1000 {
+                let k = format!("{:08}", (i * 7919) % 1000);
+                assert!(txn.put_if_absent(&mut rng, db, k.as_bytes(), b"aaaaaaaaaa").unwrap());
+                assert!(!txn.put_if_absent(&mut rng, db, k.as_bytes(), b"bbbbbbbbbb").unwrap());
+            }
+            assert_eq!(txn.len(db), 1000);
+            for i in 0..1000 {
+                let k = format!("{:08}", i);
+                assert!(!txn.compare_and_swap(&mut rng, db, k.as_bytes(), b"bbbbbbbbbb", b"cccccccccc").unwrap());
+                assert!(txn.compare_and_swap(&mut rng, db, k.as_bytes(), b"aaaaaaaaaa", b"bbbbbbbbbb").unwrap());
+                assert_eq!(txn.upsert(&mut rng, db, k.as_bytes(), b"cccccccccc").unwrap(), Some(b"bbbbbbbbbb".to_vec()));
+            }
+            assert!(!txn.compare_and_swap(&mut rng, db, b"00001000", b"cccccccccc", b"dddddddddd").unwrap());
+            assert_eq!(txn.upsert(&mut rng, db, b"00001000", b"dddddddddd").unwrap(), None);
+            assert_eq!(txn.len(db), 1001);
+            for (_, v) in txn.iter(db, b"", None).take(1000) {
+                assert_eq!(v.collect::<Vec<_>>().concat(), b"cccccccccc")
+            }
+        }
+        // With several bindings for a key, the smallest one is compared and replaced.
+        txn.put(&mut rng, &mut db, b"00000000", b"eeeeeeeeee").unwrap();
+        assert!(!txn.put_if_absent(&mut rng, &mut db, b"00000000", b"aaaaaaaaaa").unwrap());
+        assert!(!txn.compare_and_swap(&mut rng, &mut db, b"00000000", b"eeeeeeeeee", b"aaaaaaaaaa").unwrap());
+        assert!(txn.compare_and_swap(&mut rng, &mut db, b"00000000", b"cccccccccc", b"ffffffffff").unwrap());
+        assert_eq!(txn.upsert(&mut rng, &mut db, b"00000000", b"dddddddddd").unwrap(), Some(b"eeeeeeeeee".to_vec()));
+        let values:Vec<Vec<u8>> = txn.get_all(&db, b"00000000").map(|v| v.collect::<Vec<_>>().concat()).collect();
+        assert_eq!(values, vec![b"dddddddddd".to_vec(), b"ffffffffff".to_vec()]);
+        txn.set_root(0, db).unwrap();
+        txn.set_root(1, unique).unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn order_statistics() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use std::ops::Bound::*;
+        fn check<T:Transaction>(txn:&T, db:&Db, bindings:&[(String, &[u8])]) {
+            assert_eq!(txn.len(db), bindings.len() as u64);
+            for (i, &(ref k, v)) in bindings.iter().enumerate() {
+                let (key, value) = txn.nth(db, i as u64).unwrap();
+                assert_eq!(key, k.as_bytes());
+                assert!(value.cmp(v.chunks(v.len())) == std::cmp::Ordering::Equal);
+                assert_eq!(txn.rank(db, k.as_bytes(), Some(v)), i as u64);
+            }
+            assert!(txn.nth(db, bindings.len() as u64).is_none());
+        }
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let values:[&[u8];2] = [b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", b"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"];
+        let mut bindings = Vec::new();
+        // The same queries walk the pages of databases without counts.
+        for &counted in &[true, false] {
+            let mut db = txn.create_db_with_options(DbOptions { counted: counted, ..DbOptions::default() }).unwrap();
+            bindings.clear();
+            // Even keys only, with two values each.
+            for i in 0..1000 {
+                let k = format!("{:08}", ((i * 7919) % 1000) * 2);
+                for v in values.iter() {
+                    txn.put(&mut rng, &mut db, k.as_bytes(), v).unwrap();
+                    bindings.push((k.clone(), *v))
+                }
+            }
+            bindings.sort();
+            check(&txn, &db, &bindings);
+            assert_eq!(txn.rank(&db, b"00000100", None), 100);
+            assert_eq!(txn.rank(&db, b"00000101", None), 102);
+            assert_eq!(txn.count_range(&db, Included(b"00000100"), Included(b"00000200")), 102);
+            assert_eq!(txn.count_range(&db, Excluded(b"00000100"), Excluded(b"00000200")), 98);
+            assert_eq!(txn.count_range(&db, Unbounded, Excluded(b"00000010")), 10);
+            assert_eq!(txn.count_range(&db, Included(b"00001990"), Unbounded), 10);
+            assert_eq!(txn.count_range(&db, Included(b"00000200"), Included(b"00000100")), 0);
+
+            // Delete one value of every third key, and all values of every fifth key.
+            for i in 0..1000 {
+                let k = format!("{:08}", i * 2);
+                if i % 5 == 0 {
+                    txn.del(&mut rng, &mut db, k.as_bytes(), None).unwrap();
+                    txn.del(&mut rng, &mut db, k.as_bytes(), None).unwrap();
+                } else if i % 3 == 0 {
+                    txn.del(&mut rng, &mut db, k.as_bytes(), Some(values[1])).unwrap();
+                }
+            }
+            bindings.retain(|&(ref k, v)| {
+                let i = k.parse::<usize>().unwrap() / 2;
+                i % 5 != 0 && (i % 3 != 0 || v == values[0])
+            });
+            check(&txn, &db, &bindings);
+            let expected = bindings.iter().filter(|&&(ref k, _)| &k[..] >= "00000100" && &k[..] < "00000200").count();
+            assert_eq!(txn.count_range(&db, Included(b"00000100"), Excluded(b"00000200")), expected as u64);
+            txn.set_root(if counted { 0 } else { 1 }, db).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let txn = env.txn_begin().unwrap();
+        for i in 0..2 {
+            let db = txn.root(i).unwrap().unwrap();
+            check(&txn, &db, &bindings);
+        }
+    }
+
+    #[test]
+    fn order_statistics_fork() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut txn = env.mut_txn_begin().unwrap();
+        let mut db = txn.create_db_with_options(DbOptions { counted: true, ..DbOptions::default() }).unwrap();
+        for i in 0..300 {
+            let k = format!("{:08}", i * 2);
+            txn.put(&mut rng, &mut db, k.as_bytes(), b"v").unwrap();
+        }
+        // Writing to the pages shared by both databases updates their
+        // reference counts, in the middle of the put.
+        let mut fork = txn.fork_db(&mut rng, &db).unwrap();
+        txn.put(&mut rng, &mut db, b"00000001", b"v").unwrap();
+        txn.put(&mut rng, &mut fork, b"00000003", b"v").unwrap();
+        for &(ref db, extra, rank) in &[(&db, b"00000001", 1), (&fork, b"00000003", 2)] {
+            assert_eq!(txn.len(db), 301);
+            assert_eq!(txn.iter(db, b"", None).count(), 301);
+            assert_eq!(txn.rank(db, extra, None), rank);
+            assert_eq!(txn.rank(db, b"00000100", None), 51);
+            assert_eq!(txn.nth(db, rank).unwrap().0, &extra[..]);
+        }
+        txn.set_root(0, db).unwrap();
+        txn.set_root(1, fork).unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.txn_begin().unwrap();
+        for i in 0..2 {
+            let db = txn.root(i).unwrap().unwrap();
+            assert_eq!(txn.len(&db), 301);
+        }
+    }
+
+    #[test]
+    fn file_versions() -> ()
+    {
+        extern crate tempdir;
+        use std::io::{Read, Seek, SeekFrom, Write};
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        // Read the version of the file, and overwrite it with `v`.
+        let version = |v:Option<u64>| {
+            let mut f = std::fs::OpenOptions::new().read(true).write(true).open(dir.path().join("db")).unwrap();
+            let mut buf = [0;8];
+            f.read_exact(&mut buf).unwrap();
+            if let Some(v) = v {
+                f.seek(SeekFrom::Start(0)).unwrap();
+                f.write_all(&[v as u8, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+            }
+            buf[0] as u64
+        };
+        {
+            let env = Env::new(dir.path(), 1000).unwrap();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let db = txn.create_db().unwrap();
+            txn.set_root(0, db).unwrap();
+            txn.commit().unwrap();
+        }
+        // Files with only plain databases are written as version 0,
+        // which older versions can open, and upgraded when a counted
+        // or unique database is created.
+        assert_eq!(version(None), 0);
+        {
+            let env = Env::new(dir.path(), 1000).unwrap();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let db = txn.create_db().unwrap();
+            txn.set_root(1, db).unwrap();
+            txn.commit().unwrap();
+        }
+        assert_eq!(version(None), 0);
+        {
+            let env = Env::new(dir.path(), 1000).unwrap();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let db = txn.create_db_with_options(DbOptions { counted: true, ..DbOptions::default() }).unwrap();
+            txn.set_root(1, db).unwrap();
+            txn.commit().unwrap();
+        }
+        assert_eq!(version(Some(0)), transaction::CURRENT_VERSION);
+        {
+            let env = Env::new(dir.path(), 1000).unwrap();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let db = txn.create_unique_db().unwrap();
+            txn.set_root(2, db).unwrap();
+            txn.commit().unwrap();
+        }
+        assert_eq!(version(None), transaction::CURRENT_VERSION);
+        version(Some(transaction::CURRENT_VERSION + 1));
+        match Env::new(dir.path(), 1000) {
+            Err(Error::UnsupportedVersion(v)) => assert_eq!(v, transaction::CURRENT_VERSION + 1),
+            _ => panic!("opened a file of an unknown version")
+        }
+    }
+
+    #[test]
+    fn savepoints() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use rand::Rng;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 1000).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut values = Vec::new();
+        // Once with a normal transaction, once with a transaction on disjoint roots.
+        for round in 0..2 {
+            let mut txn = if round == 0 { env.mut_txn_begin().unwrap() } else { env.mut_txn_begin_roots(&[0]).unwrap() };
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+            for _ in 0..100 {
+                let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+                let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+                txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                values.push((k,v))
+            }
+            txn.set_root(0, db).unwrap();
+            let sp = txn.savepoint();
+            {
+                // Rolling back discards the later savepoints, and the
+                // ones taken in other transactions are unknown.
+                let later = txn.savepoint();
+                txn.rollback_to(sp).unwrap();
+                let _ = txn.savepoint();
+                assert!(match txn.rollback_to(later) { Err(Error::InvalidSavepoint) => true, _ => false });
+                let mut child = txn.mut_txn_begin().unwrap();
+                assert!(match child.rollback_to(sp) { Err(Error::InvalidSavepoint) => true, _ => false });
+            }
+            for _ in 0..2 {
+                let mut db = txn.root(0).unwrap().unwrap();
+                // Values of the same size as the others: mixing sizes
+                // triggers unrelated bugs in page merges.
+                for _ in 0..100 {
+                    let k: String = rand::thread_rng().gen_ascii_chars().take(50).collect();
+                    let v: String = rand::thread_rng().gen_ascii_chars().take(500).collect();
+                    txn.put(&mut rng, &mut db, k.as_bytes(), v.as_bytes()).unwrap();
+                }
+                for &(ref k, ref v) in values.iter().take(50) {
+                    txn.del(&mut rng, &mut db, k.as_bytes(), Some(v.as_bytes())).unwrap();
+                }
+                txn.set_root(0, db).unwrap();
+                txn.rollback_to(sp).unwrap();
+            }
+            let db = txn.root(0).unwrap().unwrap();
+            for &(ref k, ref v) in values.iter() {
+                assert!(txn.get(&db, k.as_bytes(), Some(v.as_bytes())).is_some())
+            }
+            assert_eq!(txn.iter(&db, b"", None).count(), values.len());
+            txn.commit().unwrap();
+        }
+        let txn = env.txn_begin().unwrap();
+        let db = txn.root(0).unwrap().unwrap();
+        for &(ref k, ref v) in values.iter() {
+            assert!(txn.get(&db, k.as_bytes(), Some(v.as_bytes())).is_some())
+        }
+        check_memory(&env, &txn, &[&db], false);
+    }
+
+    #[test]
+    fn upgrade() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use std;
+        let mut rng = rand::thread_rng();
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        {
+            let txn = env.txn_begin().unwrap();
+            assert!(txn.root(0).unwrap().is_none());
+            let mut txn = txn.upgrade().unwrap();
+            let mut root = txn.create_db().unwrap();
+            txn.put(&mut rng, &mut root, b"A", b"Value for A").unwrap();
+            txn.set_root(0, root).unwrap();
+            txn.commit().unwrap();
+        }
+        // A commit between the beginning of the transaction and its upgrade is a conflict.
+        let txn = env.txn_begin().unwrap();
+        std::thread::scope(|s| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let env = &env;
+            s.spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut txn = env.mut_txn_begin().unwrap();
+                let mut root = txn.root(0).unwrap().unwrap();
+                txn.put(&mut rng, &mut root, b"B", b"Value for B").unwrap();
+                txn.set_root(0, root).unwrap();
+                sender.send(()).unwrap();
+                // This waits for the read transaction to be released.
+                txn.commit().unwrap();
+            });
+            receiver.recv().unwrap();
+            assert!(match txn.upgrade() { Err(Error::Conflict) => true, _ => false });
+        });
+        let txn = env.txn_begin().unwrap();
+        let root = txn.root(0).unwrap().unwrap();
+        assert!(txn.get(&root, b"A",None).and_then(|mut x| x.next()) == Some(b"Value for A"));
+        assert!(txn.get(&root, b"B",None).and_then(|mut x| x.next()) == Some(b"Value for B"));
+    }
+
+    #[test]
+    fn wait_for_commit() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use std;
+        use std::time::Duration;
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        // Another environment on the same file, as another process would open it.
+        let other = Env::new(dir.path(), 100).unwrap();
+        let id = env.txn_begin().unwrap().id();
+        assert_eq!(env.wait_for_commit(id, Duration::from_millis(50)).unwrap(), None);
+        std::thread::scope(|s| {
+            let (env, other) = (&env, &other);
+            let local = s.spawn(move || env.wait_for_commit(id, Duration::from_secs(60)).unwrap());
+            let remote = s.spawn(move || other.wait_for_commit(id, Duration::from_secs(60)).unwrap());
+            let mut rng = rand::thread_rng();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut root = txn.create_db().unwrap();
+            txn.put(&mut rng, &mut root, b"A", b"Value for A").unwrap();
+            txn.set_root(0, root).unwrap();
+            txn.commit().unwrap();
+            assert_eq!(local.join().unwrap(), Some(id + 1));
+            assert_eq!(remote.join().unwrap(), Some(id + 1));
+        });
+        assert_eq!(other.wait_for_commit(id, Duration::from_millis(0)).unwrap(), Some(id + 1));
+        // A writer is already running when the other environment
+        // starts waiting.
+        std::thread::scope(|s| {
+            let mut rng = rand::thread_rng();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let other = &other;
+            let remote = s.spawn(move || other.wait_for_commit(id + 1, Duration::from_secs(60)).unwrap());
+            let mut root = txn.root(0).unwrap().unwrap();
+            txn.put(&mut rng, &mut root, b"B", b"Value for B").unwrap();
+            txn.set_root(0, root).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            txn.commit().unwrap();
+            assert_eq!(remote.join().unwrap(), Some(id + 2));
+        });
+    }
+
+    #[test]
+    fn reader_pool() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        use std;
+        use std::sync::Arc;
+        fn send_sync<T:Send+Sync>() {}
+        send_sync::<Txn>();
+        let mut rng = rand::thread_rng();
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut root = txn.create_db().unwrap();
+            txn.put(&mut rng, &mut root, b"A", b"Value for A").unwrap();
+            txn.set_root(0, root).unwrap();
+            txn.commit().unwrap();
+        }
+        let pool = env.reader_pool();
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        std::thread::scope(|s| {
+            let env = &env;
+            let pool = &pool;
+            // Read transactions can be moved to other threads.
+            let txn = env.txn_begin().unwrap();
+            s.spawn(move || {
+                let root = txn.root(0).unwrap().unwrap();
+                assert!(txn.get(&root, b"A",None).and_then(|mut x| x.next()) == Some(b"Value for A"));
+            }).join().unwrap();
+            let writer = s.spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut txn = env.mut_txn_begin().unwrap();
+                let mut root = txn.root(0).unwrap().unwrap();
+                txn.put(&mut rng, &mut root, b"B", b"Value for B").unwrap();
+                txn.set_root(0, root).unwrap();
+                txn.commit().unwrap();
+            });
+            while !env.env.commit_pending().unwrap() {
+                std::thread::yield_now()
+            }
+            // Once a commit is pending, the pool starts a new
+            // snapshot, which waits for the end of the commit.
+            let reader = s.spawn(move || {
+                let txn = pool.get().unwrap();
+                let root = txn.root(0).unwrap().unwrap();
+                assert!(txn.get(&root, b"B",None).and_then(|mut x| x.next()) == Some(b"Value for B"));
+                txn.id()
+            });
+            let id = a.id();
+            std::mem::drop(a);
+            std::mem::drop(b);
+            writer.join().unwrap();
+            assert_eq!(reader.join().unwrap(), id + 1);
+        });
+    }
+
+    #[test]
+    fn commit_if() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let mut rng = rand::thread_rng();
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        let id = env.txn_begin().unwrap().id();
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            assert!(txn.id() == id);
+            let mut root = txn.create_db().unwrap();
+            txn.put(&mut rng, &mut root, b"A", b"Value for A").unwrap();
+            txn.set_root(0, root).unwrap();
+            txn.commit_if(id).unwrap();
+        }
+        assert!(env.txn_begin().unwrap().id() == id + 1);
+        {
+            // This one started after the commit, but computed from an older snapshot.
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut root = txn.root(0).unwrap().unwrap();
+            txn.put(&mut rng, &mut root, b"B", b"Value for B").unwrap();
+            txn.set_root(0, root).unwrap();
+            assert!(match txn.commit_if(id) { Err(Error::Conflict) => true, _ => false });
+        }
+        let txn = env.txn_begin().unwrap();
+        assert!(txn.id() == id + 1);
+        let root = txn.root(0).unwrap().unwrap();
+        assert!(txn.get(&root, b"A",None).is_some());
+        assert!(txn.get(&root, b"B",None).is_none());
+    }
+
+    #[test]
+    fn last_root() -> ()
+    {
+        extern crate tempdir;
+        extern crate rand;
+        let mut rng = rand::thread_rng();
+        let dir = tempdir::TempDir::new("pijul").unwrap();
+        let env = Env::new(dir.path(), 100).unwrap();
+        // The last root is stored just before the transaction identifier.
+        let last = MAX_ROOTS - 1;
+        for i in 0..2 {
+            let id = env.txn_begin().unwrap().id();
+            let mut txn = env.mut_txn_begin().unwrap();
+            let mut root = txn.root(last).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
+            txn.put(&mut rng, &mut root, format!("{}", i).as_bytes(), b"value").unwrap();
+            txn.set_root(last, root).unwrap();
+            txn.commit().unwrap();
+            let txn = env.txn_begin().unwrap();
+            assert_eq!(txn.id(), id + 1);
+            assert_eq!(txn.iter(&txn.root(last).unwrap().unwrap(), b"", None).count(), i + 1);
+        }
+        let mut txn = env.mut_txn_begin().unwrap();
+        let id = txn.id();
+        let db = txn.create_db().unwrap();
+        assert!(match txn.set_root(MAX_ROOTS, db) { Err(Error::InvalidRoot(n)) => n == MAX_ROOTS, _ => false });
+        assert!(match txn.root(MAX_ROOTS) { Err(Error::InvalidRoot(n)) => n == MAX_ROOTS, _ => false });
+        txn.commit().unwrap();
+        let txn = env.txn_begin().unwrap();
+        assert_eq!(txn.id(), id + 1);
+        assert!(txn.root(last).unwrap().is_some());
+        assert!(match txn.root(usize::MAX) { Err(Error::InvalidRoot(_)) => true, _ => false });
+    }
+
     #[test]
     fn multiple_roots() -> ()
     {
@@ -478,7 +2858,7 @@ mod tests {
             let mut txn = env.mut_txn_begin().unwrap();
             loop {
                 let n_root: usize = rng.gen_range(0, 10);
-                let mut root = txn.root(n_root).unwrap_or_else(|| txn.create_db().unwrap());
+                let mut root = txn.root(n_root).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
 
                 let k: String = rand::thread_rng()
                     .gen_iter::<char>()
@@ -491,7 +2871,7 @@ mod tests {
                 txn.put(&mut rng, &mut root, k.as_bytes(), v.as_bytes()).unwrap();
                 random.push((n_root, k, v));
                 
-                txn.set_root(n_root, root);
+                txn.set_root(n_root, root).unwrap();
                 let r:u8 = rng.gen();
                 if r > 200 { break }
             }
@@ -500,7 +2880,7 @@ mod tests {
 
         let txn = env.txn_begin().unwrap();
         for &(ref db_name, ref k, ref v) in random.iter() {
-            let db = txn.root(*db_name).unwrap();
+            let db = txn.root(*db_name).unwrap().unwrap();
             assert!(txn.get(&db, k.as_bytes(), None).and_then(|mut x| x.next()) == Some(v.as_bytes()));
             assert!(txn.get(&db, k.as_bytes(), Some(v.as_bytes())).and_then(|mut x| x.next()) == Some(v.as_bytes()))
         }
@@ -522,7 +2902,7 @@ mod tests {
         {
             let len = 32;
             let mut txn = env.mut_txn_begin().unwrap();
-            let mut root = txn.root(42).unwrap_or_else(|| txn.create_db().unwrap());
+            let mut root = txn.root(42).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
             for ref name in db_names.iter() {
                 let mut db = txn.open_db(&root, &name[..]).unwrap_or(txn.create_db().unwrap());
                 loop {
@@ -542,12 +2922,12 @@ mod tests {
                 }
                 txn.put_db(&mut rng, &mut root, &name[..], db).unwrap();
             }
-            txn.set_root(42, root);
+            txn.set_root(42, root).unwrap();
             txn.commit().unwrap();
         }
 
         let txn = env.txn_begin().unwrap();
-        let root = txn.root(42).unwrap();
+        let root = txn.root(42).unwrap().unwrap();
         for &(ref db_name, ref k, ref v) in random.iter() {
             let db = txn.open_db(&root, &db_name[..]).unwrap();
             assert!(txn.get(&db, k.as_bytes(), None).and_then(|mut x| x.next()) == Some(v.as_bytes()));
@@ -579,7 +2959,7 @@ mod tests {
             for i in 0..40 {
                 println!("i = {:?}", i);
                 let mut txn = env.mut_txn_begin().unwrap();
-                let mut db = txn.root(0).unwrap_or_else(|| {
+                let mut db = txn.root(0).unwrap().unwrap_or_else(|| {
                     //println!("create db");
                     txn.create_db().unwrap()
                 });
@@ -622,13 +3002,13 @@ mod tests {
                 
                 if rng.gen() {
                     //txn.debug(&db,format!("/tmp/debug_{}",i),false,false);
-                    txn.set_root(0, db);
+                    txn.set_root(0, db).unwrap();
                     txn.commit().unwrap();
                     //let stats = env.statistics();
                     //println!("statistics: {:?}", stats);
                     random.push((k, v));
                 } else {
-                    txn.set_root(0, db);
+                    txn.set_root(0, db).unwrap();
                     println!("abort !");
                     txn.abort()
                     // std::mem::drop(txn);
@@ -637,7 +3017,7 @@ mod tests {
             }
         }
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         for &(ref k, ref v) in random.iter() {
             assert!(txn.get(&db, k.as_bytes(), None).and_then(|x| {
                 buf.clear();
@@ -697,26 +3077,26 @@ mod tests {
             .collect();
         {
             let mut txn = env.mut_txn_begin().unwrap();
-            let mut db = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
             txn.put(&mut rng, &mut db, k0.as_bytes(), v0.as_bytes()).unwrap();
             txn.put(&mut rng, &mut db, k1.as_bytes(), v1.as_bytes()).unwrap();
-            txn.set_root(0, db);
+            txn.set_root(0, db).unwrap();
             txn.commit().unwrap();
         }
 
         {
             let mut txn = env.mut_txn_begin().unwrap();
-            let mut db = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
             //txn.debug(&db,"/tmp/before");
             txn.del(&mut rng, &mut db, k0.as_bytes(), Some(v0.as_bytes())).unwrap();
             //txn.debug(&db,"/tmp/after");
-            txn.set_root(0, db);
+            txn.set_root(0, db).unwrap();
             txn.commit().unwrap();
         }
 
         random.push((k1,v1));
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         for &(ref k, ref v) in random.iter() {
             assert!(txn.get(&db, k.as_bytes(), None).and_then(|x| {
                 buf.clear();
@@ -757,11 +3137,11 @@ mod tests {
                 .collect();
 
             let mut txn = env.mut_txn_begin().unwrap();
-            let mut db = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
             txn.put(&mut rng, &mut db, k0.as_bytes(), v0.as_bytes()).unwrap();
 
             txn.debug(&[&db], format!("/tmp/before_{}",i), false, false);
-            txn.set_root(0, db);
+            txn.set_root(0, db).unwrap();
             txn.commit().unwrap();
         }
     }
@@ -793,33 +3173,33 @@ mod tests {
                 .collect();
 
             let mut txn = env.mut_txn_begin().unwrap();
-            let mut db = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
             txn.put(&mut rng, &mut db, k0.as_bytes(), v0.as_bytes()).unwrap();
 
             random.insert(k0,v0);
             
             txn.debug(&[&db], format!("/tmp/before_{}",i), false, false);
-            txn.set_root(0, db);
+            txn.set_root(0, db).unwrap();
             txn.commit().unwrap();
         }
         debug!("put done");
         {
             let txn = env.txn_begin().unwrap();
-            let db = txn.root(0).unwrap();
+            let db = txn.root(0).unwrap().unwrap();
             check_memory(&env, &txn, &[&db], true);
         }
         let mut i = 0;
         for (ref k, ref v) in random.iter() {
             debug!("del i = {:?}, k = {:?}", i, k);
             let mut txn = env.mut_txn_begin().unwrap();
-            let mut db = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+            let mut db = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
             txn.del(&mut rng, &mut db, k.as_bytes(), Some(v.as_bytes())).unwrap();
             txn.debug(&[&db], format!("/tmp/after_{}",i), false, false);
-            txn.set_root(0, db);
+            txn.set_root(0, db).unwrap();
             txn.commit().unwrap();
 
             let txn = env.txn_begin().unwrap();
-            let db = txn.root(0).unwrap();
+            let db = txn.root(0).unwrap().unwrap();
             check_memory(&env, &txn, &[&db], true);
 
             i+=1;
@@ -834,7 +3214,7 @@ mod tests {
         use std::collections::{HashMap};
         use super::txn::{Page,LoadPage,P, UnsafeValue, PageIterator};
         // let txn = env.txn_begin();
-        // let db = txn.root(0).unwrap();
+        // let db = txn.root(0).unwrap().unwrap();
         fn count_pages<T:Transaction>(txn:&T, page:&Page, pages:&mut HashMap<u64,usize>, value_pages:&mut HashMap<u64,usize>) {
 
             let mut follow = false;
@@ -963,7 +3343,7 @@ mod tests {
         let env = Env::new(dir.path(), 5000).unwrap();
         leakproof_put(&env, n_insertions, value_size);
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         check_memory(&env, &txn, &[&db], true);
     }
 
@@ -978,7 +3358,7 @@ mod tests {
         let env = Env::new(dir.path(), 5000).unwrap();
         leakproof_put(&env, n_insertions, value_size);
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         check_memory(&env, &txn, &[&db], true);
     }
 
@@ -993,7 +3373,7 @@ mod tests {
         let env = Env::new(dir.path(), 5000).unwrap();
         leakproof_put(&env, n_insertions, value_size);
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         check_memory(&env, &txn, &[&db], true);
     }
 
@@ -1010,7 +3390,7 @@ mod tests {
         leakproof_put_del(&env, n_insertions, key_size, value_size);
         println!("checking");
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         check_memory(&env, &txn, &[&db], true);
     }
 
@@ -1027,7 +3407,7 @@ mod tests {
         leakproof_put_del(&env, n_insertions, key_size, value_size);
         println!("checking");
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         check_memory(&env, &txn, &[&db], true);
     }
 
@@ -1044,7 +3424,7 @@ mod tests {
         leakproof_put_del(&env, n_insertions, key_size, value_size);
         println!("checking");
         let txn = env.txn_begin().unwrap();
-        let db = txn.root(0).unwrap();
+        let db = txn.root(0).unwrap().unwrap();
         check_memory(&env, &txn, &[&db], true);
     }
 
@@ -1060,7 +3440,7 @@ mod tests {
         let dir = tempdir::TempDir::new("pijul").unwrap();
         let env = Env::new(dir.path(), 100).unwrap();
         let mut txn = env.mut_txn_begin().unwrap();
-        let mut root = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+        let mut root = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
         println!("root: {:?}", root);
 
         let common = b"test_key";
@@ -1076,14 +3456,14 @@ mod tests {
         let mut root2 = txn.fork_db(&mut rng, &root).unwrap();
         txn.put(&mut rng, &mut root, key0, key0_value).unwrap();
         txn.put(&mut rng, &mut root2, key1, key1_value).unwrap();
-        txn.set_root(0, root);
-        txn.set_root(1, root2);
+        txn.set_root(0, root).unwrap();
+        txn.set_root(1, root2).unwrap();
         txn.commit().unwrap();
         println!("committed");
 
         let txn = env.txn_begin().unwrap();
-        let root0 = txn.root(0).unwrap();
-        let root1 = txn.root(1).unwrap();
+        let root0 = txn.root(0).unwrap().unwrap();
+        let root1 = txn.root(1).unwrap().unwrap();
 
         assert!(txn.get(&root0, common, None).is_some());
         assert!(txn.get(&root0, key0, None).is_some());
@@ -1108,7 +3488,7 @@ mod tests {
         let tmp = tempdir::TempDir::new("pijul").unwrap();
         let env = Env::new(dir.path(), 100).unwrap();
         let mut txn = env.mut_txn_begin().unwrap();
-        let mut root = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+        let mut root = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
         println!("root: {:?}", root);
 
         let common = b"test_key";
@@ -1128,14 +3508,14 @@ mod tests {
         txn.put(&mut rng, &mut root, key0, key0_value).unwrap();
         txn.put(&mut rng, &mut root2, key1, key1_value).unwrap();
         txn.del(&mut rng, &mut root2, common0, None).unwrap();
-        txn.set_root(0, root);
-        txn.set_root(1, root2);
+        txn.set_root(0, root).unwrap();
+        txn.set_root(1, root2).unwrap();
         txn.commit().unwrap();
         println!("committed");
 
         let txn = env.txn_begin().unwrap();
-        let root0 = txn.root(0).unwrap();
-        let root1 = txn.root(1).unwrap();
+        let root0 = txn.root(0).unwrap().unwrap();
+        let root1 = txn.root(1).unwrap().unwrap();
 
         txn.debug(&[&root0, &root1], tmp.path().join(format!("after")), false, false);
         debug!("tmp: {:?}", tmp.path());
@@ -1184,7 +3564,7 @@ mod tests {
         let mut values1 = HashMap::new();
         
         let mut txn = env.mut_txn_begin().unwrap();
-        let mut root0 = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+        let mut root0 = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
 
         for i in 0..n_insertions {
             println!("i = {:?}", i);
@@ -1243,15 +3623,15 @@ mod tests {
         }
         txn.debug(&[&root0, &root1], tmp_path.join("forked"), false, false);
 
-        txn.set_root(0, root0);
-        txn.set_root(1, root1);
+        txn.set_root(0, root0).unwrap();
+        txn.set_root(1, root1).unwrap();
 
         txn.commit().unwrap();
 
         let txn = env.txn_begin().unwrap();
         let rc_db = txn.rc().unwrap();
-        let db0 = txn.root(0).unwrap();
-        let db1 = txn.root(1).unwrap();
+        let db0 = txn.root(0).unwrap().unwrap();
+        let db1 = txn.root(1).unwrap().unwrap();
         check_memory(&env, &txn, &[&db0, &db1, &rc_db], true);
 
     }
@@ -1346,7 +3726,7 @@ mod tests {
         let mut values1 = HashMap::new();
         
         let mut txn = env.mut_txn_begin().unwrap();
-        let mut root0 = txn.root(0).unwrap_or_else(|| txn.create_db().unwrap());
+        let mut root0 = txn.root(0).unwrap().unwrap_or_else(|| txn.create_db().unwrap());
 
         for _ in 0..n_insertions {
             let k0: String = rand::thread_rng()
@@ -1398,15 +3778,15 @@ mod tests {
         }
         txn.debug_concise(&[&root0, &root1], tmp_path.join("forked"));
 
-        txn.set_root(0, root0);
-        txn.set_root(1, root1);
+        txn.set_root(0, root0).unwrap();
+        txn.set_root(1, root1).unwrap();
 
 
         txn.commit().unwrap();
         debug!("tmp: {:?}", tmp_path);
         let txn = env.txn_begin().unwrap();
-        let db0 = txn.root(0).unwrap();
-        let db1 = txn.root(1).unwrap();
+        let db0 = txn.root(0).unwrap().unwrap();
+        let db1 = txn.root(1).unwrap().unwrap();
 
         let (used_pages, value_pages) = check_rc(&txn, &[&db0, &db1]);
 
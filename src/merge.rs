@@ -179,7 +179,7 @@ pub fn merge_children_right<R:Rng, T>(
     };
     debug!("child_page_occupied {:?} {:?}", child_page.occupied(), deleted_size);
 
-    if right_sibling_size + child_page.occupied() - deleted_size - 24 + next_record_size <= PAGE_SIZE as u16 {
+    if right_sibling_size + child_page.occupied() - deleted_size - child_page.header_size() + next_record_size <= PAGE_SIZE as u16 {
         // Merge child_page into its right sibling.
         if page_will_be_dup {
             if let UnsafeValue::O { offset,.. } = next_value {
@@ -189,7 +189,7 @@ pub fn merge_children_right<R:Rng, T>(
 
         // Check the need for compaction of the right sibling.
         let needs_compaction = {
-            let extra_size =  child_page.occupied() - deleted_size - 24 + next_record_size;
+            let extra_size =  child_page.occupied() - deleted_size - child_page.header_size() + next_record_size;
             let off = right_sibling.can_alloc(extra_size);
             off+extra_size > PAGE_SIZE as u16
         };
@@ -285,7 +285,7 @@ pub fn merge_children_left<R:Rng, T>(
     };
     debug!("child_page_occupied {:?} {:?}", child_page.occupied(), deleted_size);
     // If there's enough space in the left sibling, merge. Else, return Res::Nothing { .. }.
-    if left_sibling_size + child_page.occupied() - deleted_size - 24 + next_record_size <= PAGE_SIZE as u16 {
+    if left_sibling_size + child_page.occupied() - deleted_size - child_page.header_size() + next_record_size <= PAGE_SIZE as u16 {
         if page_will_be_dup {
             if let UnsafeValue::O { offset,.. } = next_value {
                 try!(incr_rc(rng, txn, offset))
@@ -294,7 +294,7 @@ pub fn merge_children_left<R:Rng, T>(
 
         // Check the need for compaction of the right sibling.
         let needs_compaction = {
-            let extra_size =  child_page.occupied() - deleted_size - 24 + next_record_size;
+            let extra_size =  child_page.occupied() - deleted_size - child_page.header_size() + next_record_size;
             let off = left_sibling.can_alloc(extra_size);
             off+extra_size > PAGE_SIZE as u16
         };
@@ -401,7 +401,7 @@ pub fn merge_children_replace<R:Rng, T>(
 
     debug!("child_page_occupied {:?} {:?}", child_page.occupied(), deleted_size);
     // If we can merge, do it. Else, return Res::Nothing { .. }.
-    if left_sibling_size + child_page_size - 24 + next_record_size - deleted_size <= PAGE_SIZE as u16 {
+    if left_sibling_size + child_page_size - child_page.header_size() + next_record_size - deleted_size <= PAGE_SIZE as u16 {
 
         // Already increased when we deleted it from the smallest descendant page.
         /*if page_will_be_dup || child_will_be_dup {
@@ -411,7 +411,7 @@ pub fn merge_children_replace<R:Rng, T>(
         }*/
         // Check the need for compaction of the right sibling.
         let needs_compaction = {
-            let extra_size =  child_page.occupied() - deleted_size - 24 + next_record_size;
+            let extra_size =  child_page.occupied() - deleted_size - child_page.header_size() + next_record_size;
             let off = left_sibling.can_alloc(extra_size);
             off+extra_size > PAGE_SIZE as u16
         };
@@ -127,6 +127,7 @@ pub fn alloc_value<T>(txn:&mut MutTxn<T>, value: &[u8]) -> Result<UnsafeValue,Er
     let mut p_value = value.as_ptr();
     let mut page = try!(txn.alloc_page());
     let first_page = page.page_offset();
+    txn.txn.stats.value_pages += 1;
     unsafe {
         loop {
             if len <= PAGE_SIZE {
@@ -137,6 +138,7 @@ pub fn alloc_value<T>(txn:&mut MutTxn<T>, value: &[u8]) -> Result<UnsafeValue,Er
                 p_value = p_value.offset((PAGE_SIZE-8) as isize);
                 len -= PAGE_SIZE - 8;
                 let next_page = try!(txn.alloc_page());
+                txn.txn.stats.value_pages += 1;
                 *(page.offset(0) as *mut u64) = next_page.page_offset().to_le();
                 page = next_page
             }
@@ -220,7 +222,8 @@ pub fn copy_page<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, p:&Page, old_levels:&[
 
         let mut page = try!(txn.alloc_page());
         debug!("copy_page: allocated {:?}", page.page_offset());
-        page.init();
+        txn.txn.stats.copied_pages += 1;
+        page.init_with(p.is_counted());
         let mut n = 0;
         let mut levels:[u16;N_LEVELS] = [FIRST_HEAD;N_LEVELS];
         
@@ -330,6 +333,7 @@ pub fn cow_pinpointing<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, old_le
             }
             transaction::Cow::MutPage(p) => {
                 let p = MutPage { page:p };
+                txn.touched.insert(p.page_offset());
                 std::ptr::copy_nonoverlapping(old_levels.as_ptr(), pinpoints.as_mut_ptr(), old_levels.len());
                 if forgetting_next {
                     let next = u16::from_le(*(p.offset(old_levels[0] as isize) as *const u16));
@@ -419,21 +423,21 @@ fn test_insert(value_size:usize) {
                 UnsafeValue::S { p:value.as_ptr(), len:value.len() as u32 }
             };
 
-            match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false) {
-                Ok(Res::Ok { page:page_,.. }) => {
+            match insert(&mut rng, &mut txn, Cow::from_mut_page(page), key, value, 0, false, Mode::Insert, None, None) {
+                Ok(Inserted::Res(Res::Ok { page:page_,.. })) => {
                     page = page_
                 },
-                Ok(Res::Nothing { page:page_ }) => {
+                Ok(Inserted::Res(Res::Nothing { page:page_ })) => {
                     //println!("already present");
                     page = page_.unwrap_mut()
                 },
-                Ok(x) => {
+                Ok(Inserted::Res(x)) => {
                     page = root_split(&mut rng, &mut txn, x).unwrap()
                 },
                 _ => panic!("")
             }
 
-            let db = Db { root_num: -1, root: page.page_offset() };
+            let db = Db { root_num: -1, root: page.page_offset(), unique: false };
             debug!("debugging");
             txn.debug(&[&db], format!("/tmp/after_{}",i), false, false);
             for &(ref key, _) in random.iter() {
@@ -444,7 +448,7 @@ fn test_insert(value_size:usize) {
         random.push((key,value));
     }
 
-    let db = Db { root_num: -1, root: page.page_offset() };
+    let db = Db { root_num: -1, root: page.page_offset(), unique: false };
     txn.debug(&[&db], format!("/tmp/debug"), false, false);
     for &(ref key, _) in random.iter() {
         assert!(txn.get(&db, key.as_bytes(), None).is_some())
@@ -548,14 +552,46 @@ pub fn set_levels<T,P:super::txn::P>(txn:&MutTxn<T>, page:&P, key:&[u8], value:O
 
 
 
-pub fn insert<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], value:UnsafeValue, right_page:u64, parent_will_be_dup:bool) -> Result<Res,Error> {
+/// What `insert` does with the existing bindings of a key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode<'a> {
+    /// Add a new binding.
+    Insert,
+    /// Replace the smallest binding of the key, or add one if there is none.
+    Replace,
+    /// Add a binding only if the key has none.
+    IfAbsent,
+    /// Replace the smallest binding of the key, only if its value is this one.
+    IfEqual(&'a [u8]),
+}
+
+/// Outcome of `insert` on a subtree.
+pub enum Inserted {
+    Res(Res),
+    /// The subtree has no binding of the key, and `mode` replaces the
+    /// binding after it (see `insert`).
+    Absent,
+    /// The new value of the smallest binding of the key is not
+    /// smaller than the next value of that key, the binding must be
+    /// deleted and inserted again.
+    OutOfOrder,
+}
+
+/// Insert a binding in the tree rooted at `page`. Unless `mode` is
+/// `Mode::Insert`, only keys are compared, and if the smallest
+/// binding of `key` is replaced, its value is copied to `old`, if
+/// given. `above` is the value of the binding following this
+/// subtree, if its key is `key`: the smallest binding of `key` is
+/// then in this subtree, or it is that binding.
+pub fn insert<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], value:UnsafeValue, right_page:u64, parent_will_be_dup:bool, mode:Mode, above:Option<UnsafeValue>, mut old:Option<&mut Option<Vec<u8>>>) -> Result<Inserted,Error> {
     debug!("insert page = {:?}", page.page_offset());
     let mut eq = false;
     let mut levels = [0;N_LEVELS];
-    set_levels(txn, &page, key, Some(value), &mut levels[..], &mut eq);
+    let by_key = mode != Mode::Insert;
+    set_levels(txn, &page, key, if by_key { None } else { Some(value) }, &mut levels[..], &mut eq);
     debug!("levels={:?}", levels);
-    if eq {
-        Ok(Res::Nothing{page:page})
+    if eq && (!by_key || mode == Mode::IfAbsent) {
+        Ok(Inserted::Res(Res::Nothing{page:page}))
     } else {
         let child_page = page.right_child(levels[0]);
         let page_rc = get_rc(txn, page.page_offset());
@@ -563,11 +599,26 @@ pub fn insert<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], valu
         debug!("page_rc = {:?} {:?}", parent_will_be_dup, page_rc);
         if child_page > 0 && right_page == 0 {
             debug!("inserting in child page {:?}", child_page);
-            // Insert in the page below.
+            // Insert in the page below. If there are smaller bindings
+            // of the same key, they are there.
             let next_page = txn.load_cow_page(child_page);
-
-            match try!(insert(rng, txn, next_page, key, value, right_page, page_will_be_dup)) {
-                Res::Nothing{..} => Ok(Res::Nothing { page:page }),
+            let child_above = if eq {
+                unsafe {
+                    let next = u16::from_le(*(page.offset(levels[0] as isize) as *const u16));
+                    Some(read_key_value(page.offset(next as isize)).1)
+                }
+            } else {
+                above
+            };
+            let res = match try!(insert(rng, txn, next_page, key, value, right_page, page_will_be_dup, mode, child_above, old.as_deref_mut())) {
+                Inserted::Res(res) => res,
+                Inserted::Absent if eq => unsafe {
+                    return replace_local(rng, txn, page, key, value, &mut levels, parent_will_be_dup, page_will_be_dup, mode, above, old)
+                },
+                x => return Ok(x)
+            };
+            match res {
+                Res::Nothing{..} => Ok(Inserted::Res(Res::Nothing { page:page })),
                 Res::Ok { page:next_page } => {
                     debug!("Child returned ok: {:?}", next_page);
 
@@ -577,7 +628,8 @@ pub fn insert<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], valu
                     if !page_will_be_dup {
                         let page = try!(cow_pinpointing(rng, txn, page, &levels[..], &mut new_levels[..], false, false,
                                                         next_page.page_offset()));
-                        Ok(Res::Ok { page:page })
+                        txn.path_push(page.page_offset(), new_levels[0]);
+                        Ok(Inserted::Res(Res::Ok { page:page }))
                     } else {
                         // Decrement the counter for the first page with RC>1 on the path from the root.
                         if !parent_will_be_dup && page_rc > 1 {
@@ -586,7 +638,8 @@ pub fn insert<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], valu
                         let page =
                             try!(copy_page(rng, txn, &page.as_page(), &levels[..], &mut new_levels[..], false, false,
                                            next_page.page_offset(), true));
-                        Ok(Res::Ok { page: page })
+                        txn.path_push(page.page_offset(), new_levels[0]);
+                        Ok(Inserted::Res(Res::Ok { page: page }))
                     }
                 },
                 Res::Split { key_ptr,key_len,value:value_,left,right,free_page } => {
@@ -606,20 +659,147 @@ pub fn insert<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], valu
                     if !page_will_be_dup && free_page > 0 {
                         try!(free(rng, txn, free_page));
                     }
-                    result
+                    result.map(Inserted::Res)
                 },
                 Res::Underfull {..} => unreachable!()
             }
+        } else if eq {
+            unsafe {
+                replace_local(rng, txn, page, key, value, &mut levels, parent_will_be_dup, page_will_be_dup, mode, above, old)
+            }
+        } else if above.is_some() {
+            Ok(Inserted::Absent)
+        } else if let Mode::IfEqual(_) = mode {
+            // There is no binding to compare with.
+            Ok(Inserted::Res(Res::Nothing { page:page }))
         } else {
             debug!("inserting here");
             // No child page, insert on this page.
-            unsafe {
-                full_local_insert(rng, txn, page, key, value, right_page, &mut levels, 0, parent_will_be_dup, page_will_be_dup)
+            let res = unsafe {
+                try!(full_local_insert(rng, txn, page, key, value, right_page, &mut levels, 0, parent_will_be_dup, page_will_be_dup))
+            };
+            if let Res::Ok { ref page } = res {
+                txn.path_start(page.page_offset(), levels[0])
+            }
+            Ok(Inserted::Res(res))
+        }
+    }
+}
+
+// The value of the binding following the one at `off` on `page`, if
+// its key is `key`. `above` is the binding following the subtree of
+// `page`, as in `insert`.
+unsafe fn next_value_of_key<T>(txn:&MutTxn<T>, page:&Cow, off:u16, key:&[u8], above:Option<UnsafeValue>) -> Option<UnsafeValue> {
+    let mut child = page.right_child(off);
+    let (next_key, next_value) =
+        if child == 0 {
+            let next = u16::from_le(*(page.offset(off as isize) as *const u16));
+            if next == NIL {
+                return above
+            }
+            read_key_value(page.offset(next as isize))
+        } else {
+            // The smallest binding of the subtree of `child`.
+            loop {
+                let p = txn.load_page(child);
+                let c = right_child(&p, FIRST_HEAD);
+                if c == 0 {
+                    break read_key_value(p.offset(next_at_level(&p, FIRST_HEAD, 0) as isize))
+                }
+                child = c
+            }
+        };
+    if next_key == key { Some(next_value) } else { None }
+}
+
+/// Replace the value of the binding just after `levels[0]` on `page`,
+/// whose key is `key`, keeping its right child. If the new binding
+/// fits in the space of the old one, and the page is not shared, the
+/// value is rewritten without changing the lists of the page. The
+/// next binding is only looked up if the new value is larger than
+/// the old one.
+unsafe fn replace_local<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], value:UnsafeValue, levels:&mut [u16], parent_will_be_dup: bool, page_will_be_dup:bool, mode:Mode, above:Option<UnsafeValue>, old_bytes:Option<&mut Option<Vec<u8>>>) -> Result<Inserted, Error> {
+    let old = u16::from_le(*(page.offset(levels[0] as isize) as *const u16));
+    let (_, old_value) = read_key_value(page.offset(old as isize));
+    let copy = || Value::from_unsafe(&old_value, txn).fold(Vec::new(), |mut v, x| { v.extend_from_slice(x); v });
+    if let Mode::IfEqual(expected) = mode {
+        let bytes = copy();
+        let equal = &bytes[..] == expected;
+        if let Some(old_bytes) = old_bytes {
+            *old_bytes = Some(bytes)
+        }
+        if !equal {
+            return Ok(Inserted::Res(Res::Nothing { page:page }))
+        }
+    } else if let Some(old_bytes) = old_bytes {
+        *old_bytes = Some(copy())
+    }
+    match (Value::from_unsafe(&value, txn)).cmp(Value::from_unsafe(&old_value, txn)) {
+        Ordering::Equal => return Ok(Inserted::Res(Res::Nothing { page:page })),
+        Ordering::Greater => {
+            if let Some(next) = next_value_of_key(txn, &page, old, key, above) {
+                if (Value::from_unsafe(&value, txn)).cmp(Value::from_unsafe(&next, txn)) != Ordering::Less {
+                    return Ok(Inserted::OutOfOrder)
+                }
+            }
+        },
+        Ordering::Less => {}
+    }
+    let old_child = page.right_child(old);
+    let old_size = record_size(key.len(), old_value.len() as usize);
+    let size = record_size(key.len(), value.len() as usize);
+    if !page_will_be_dup && size <= old_size {
+        let mut new_levels = [0;N_LEVELS];
+        let mut page = try!(cow_pinpointing(rng, txn, page, levels, &mut new_levels, false, false, 0));
+        if let UnsafeValue::O { offset, len } = old_value {
+            try!(free_value(rng, txn, offset, len))
+        }
+        let off = page.level(new_levels[0], 0);
+        page.write_key_value(off, key.as_ptr(), key.len(), value);
+        *(page.p_occupied()) = (page.occupied() - old_size + size).to_le();
+        txn.path_start(page.page_offset(), off);
+        return Ok(Inserted::Res(Res::Ok { page:page }))
+    }
+    if page_will_be_dup {
+        if !parent_will_be_dup {
+            try!(decr_rc(rng, txn, page.page_offset()))
+        }
+        // The child of the old binding is not copied with the page,
+        // but becomes the child of the new one.
+        if old_child > 0 {
+            try!(incr_rc(rng, txn, old_child))
+        }
+    }
+    if page.occupied() - old_size + size < PAGE_SIZE as u16 {
+        let mut new_levels = [0;N_LEVELS];
+        let mut page =
+            if page_will_be_dup {
+                // The old value is still referenced by the old page.
+                try!(copy_page(rng, txn, &page.as_page(), levels, &mut new_levels, true, false, 0, true))
+            } else if page.first_free() + size < PAGE_SIZE as u16 {
+                try!(cow_pinpointing(rng, txn, page, levels, &mut new_levels, true, true, 0))
+            } else {
+                // Compact the page, by considering it non mutable.
+                try!(cow_pinpointing(rng, txn, page.as_nonmut(), levels, &mut new_levels, true, true, 0))
+            };
+        let off = page.can_alloc(size);
+        local_insert_at(rng, &mut page, key, value, old_child, off, size, &mut new_levels);
+        txn.path_start(page.page_offset(), off);
+        Ok(Inserted::Res(Res::Ok { page:page }))
+    } else {
+        debug!("splitting, key = {:?}", std::str::from_utf8(key));
+        if !page_will_be_dup {
+            if let UnsafeValue::O { offset, len } = old_value {
+                try!(free_value(rng, txn, offset, len))
             }
         }
+        split_page(rng, txn, &page, key, value, old_child, page_will_be_dup, old, NIL, 0).map(Inserted::Res)
     }
 }
 
+/// Insert a binding after `levels[0]` on `page`, splitting it if
+/// needed. If the page does not split, `levels[0]` is then the offset
+/// of the new binding in the returned page.
 pub unsafe fn full_local_insert<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, key:&[u8], value:UnsafeValue, right_page:u64, levels:&mut [u16], left_page:u64, parent_will_be_dup: bool, page_will_be_dup:bool) -> Result<Res, Error> {
     let size = record_size(key.len(), value.len() as usize);
     let mut new_levels = [0;N_LEVELS];
@@ -645,6 +825,7 @@ pub unsafe fn full_local_insert<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:C
                 };
             local_insert_at(rng, &mut page, key, value, right_page,
                             off, size, &mut new_levels[..]);
+            levels[0] = off;
             Ok(Res::Ok { page:page })
         } else {
             debug!("splitting, key = {:?}", std::str::from_utf8(key));
@@ -665,6 +846,7 @@ pub unsafe fn full_local_insert<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:C
             let mut page = try!(copy_page(rng, txn, &p, levels, &mut new_levels, false, false, left_page, true));
             local_insert_at(rng, &mut page, key, value, right_page,
                             off, size, &mut new_levels[..]);
+            levels[0] = off;
             Ok(Res::Ok { page:page })
         } else {
             debug!("splitting, key = {:?}", std::str::from_utf8(key));
@@ -740,9 +922,9 @@ pub unsafe fn split_page<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>,page:&Cow,
     debug!("split {:?} {:?}", page.page_offset(), page_will_be_dup);
     debug!("split {:?}", std::str::from_utf8(key));
     let mut left = try!(txn.alloc_page());
-    left.init();
+    left.init_with(page.is_counted());
     let mut right = try!(txn.alloc_page());
-    right.init();
+    right.init_with(page.is_counted());
     debug!("split allocated {:?} {:?}", left.page_offset(), right.page_offset());
     *((left.offset(FIRST_HEAD as isize) as *mut u64).offset(2)) =
         if translate_index == 0 {
@@ -756,7 +938,7 @@ pub unsafe fn split_page<R:Rng,T>(rng:&mut R, txn:&mut MutTxn<T>,page:&Cow,
     // Loop through the values of the page, in order, and insert them to left in order.
     // Stop whenever both pages can include one extra entry after inserting the input entry to this function.
 
-    let mut left_bytes = 24;
+    let mut left_bytes = page.header_size();
     let mut left_levels = [FIRST_HEAD;N_LEVELS];
     let mut right_levels = [FIRST_HEAD;N_LEVELS];
     let mut middle = None;
@@ -877,7 +1059,7 @@ pub fn root_split<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, x:Res) -> Result<Mut
     debug!("ROOT SPLIT");
     if let Res::Split { left,right,key_ptr,key_len,value,free_page } = x {
         let mut page = try!(txn.alloc_page());
-        page.init();
+        page.init_with(left.is_counted());
         page.set_right_child(FIRST_HEAD, left.page_offset());
         let mut levels = [0;N_LEVELS];
         let size = record_size(key_len, value.len() as usize);
@@ -894,8 +1076,18 @@ pub fn root_split<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, x:Res) -> Result<Mut
 
 
 pub fn put<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, db: &mut Db, key: &[u8], value: &[u8])->Result<bool,Error> {
-    assert!(key.len() < MAX_KEY_SIZE);
-    let root_page = Cow { cow: txn.txn.load_cow_page(db.root) };
+    let mode = if db.unique { Mode::Replace } else { Mode::Insert };
+    put_(rng, txn, db, key, value, mode, None)
+}
+
+/// Insert a binding according to `mode` (see `insert`), and return
+/// whether the database changed. If the smallest binding of `key`
+/// would move past the next one, it is deleted, and the new binding
+/// is inserted again.
+pub fn put_<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, db: &mut Db, key: &[u8], value: &[u8], mode:Mode, old:Option<&mut Option<Vec<u8>>>)->Result<bool,Error> {
+    debug_assert!(key.len() < MAX_KEY_SIZE);
+    let root_page = txn.load_cow_page(db.root);
+    let bytes = value;
     let value = if value.len() > VALUE_SIZE_THRESHOLD {
         try!(alloc_value(txn,value))
     } else {
@@ -903,12 +1095,67 @@ pub fn put<R:Rng,T>(rng:&mut R, txn: &mut MutTxn<T>, db: &mut Db, key: &[u8], va
     };
     debug!("key = {:?}", std::str::from_utf8(key));
     unsafe { debug!("value = {:?}", Value::from_unsafe(&value, txn)) }
-    match try!(insert(rng, txn, root_page, key, value, 0, false)) {
-        Res::Nothing { .. } => Ok(false),
-        Res::Ok { page,.. } => { db.root = page.page_offset(); Ok(true) }
-        x => {
+    txn.path_clear();
+    match try!(insert(rng, txn, root_page, key, value, 0, false, mode, None, old)) {
+        Inserted::Res(Res::Nothing { .. }) => {
+            if let UnsafeValue::O { offset, len } = value {
+                try!(free_value(rng, txn, offset, len))
+            }
+            Ok(false)
+        },
+        Inserted::Res(Res::Ok { page,.. }) => {
+            db.root = page.page_offset();
+            update_counts(txn, db.root);
+            Ok(true)
+        }
+        Inserted::Res(x) => {
             db.root = try!(root_split(rng,txn,x)).page_offset();
+            update_counts(txn, db.root);
             Ok(true)
         }
+        Inserted::OutOfOrder => {
+            if let UnsafeValue::O { offset, len } = value {
+                try!(free_value(rng, txn, offset, len))
+            }
+            try!(super::del::del(rng, txn, db, key, None));
+            try!(put_(rng, txn, db, key, bytes, Mode::Insert, None));
+            Ok(true)
+        }
+        Inserted::Absent => unreachable!()
+    }
+}
+
+/// Update the number of bindings in the subtrees of the pages written
+/// by the current operation, bottom-up from `page`, and return the
+/// count of `page`. The other pages have not changed, and their count
+/// is still valid. Databases created without counts are left as
+/// they are. The set of written pages is cleared only at the end of
+/// the whole operation (see `MutTxn::atomically`), since updates of
+/// the reference counts run in the middle of it.
+pub fn update_counts<T>(txn:&mut MutTxn<T>, page:u64) -> u64 {
+    if !txn.load_page(page).is_counted() {
+        return 0
+    }
+    if !txn.touched.remove(&page) {
+        return txn.load_page(page).count()
+    }
+    // Pages of previous transactions or savepoints are not written
+    // in place, and were counted when they were written.
+    let mut p = match txn.txn.load_cow_page(page) {
+        transaction::Cow::MutPage(p) => MutPage { page:p },
+        transaction::Cow::Page(p) => return Page { page:p }.count()
+    };
+    let mut count = 0;
+    let mut children = vec![p.right_child(FIRST_HEAD)];
+    for (_, _, _, r) in PageIterator::new(&p, 0) {
+        count += 1;
+        children.push(r)
+    }
+    for child in children {
+        if child > 0 {
+            count += update_counts(txn, child)
+        }
     }
+    p.set_count(count);
+    count
 }
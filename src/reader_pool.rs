@@ -0,0 +1,49 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Pool of read transactions: as long as no commit is waiting, all
+// threads asking for a snapshot get the same read transaction. Since
+// commits wait for all readers to finish, a snapshot handed out by
+// the pool is never outdated; once a commit is pending, the pool
+// starts a new transaction, which waits for the end of that commit.
+
+use std::sync::{Arc, Mutex, Weak};
+use super::{Env, Txn, Error};
+
+/// A pool of read transactions, shared between threads.
+pub struct ReaderPool<'env> {
+    env: &'env Env,
+    current: Mutex<Weak<Txn<'env>>>,
+}
+
+impl<'env> ReaderPool<'env> {
+    #[doc(hidden)]
+    pub fn new(env: &'env Env) -> ReaderPool<'env> {
+        ReaderPool {
+            env: env,
+            current: Mutex::new(Weak::new()),
+        }
+    }
+
+    /// Get a read transaction on the latest snapshot. If another
+    /// thread still holds a transaction from this pool, and no commit
+    /// is waiting, that transaction is reused instead of starting a
+    /// new one.
+    ///
+    /// Commits wait until all the returned transactions are dropped,
+    /// so they should be short-lived. In particular, calling this
+    /// while holding a transaction from the same pool might deadlock
+    /// if a commit is pending.
+    pub fn get(&self) -> Result<Arc<Txn<'env>>, Error> {
+        let mut current = try!(self.current.lock());
+        if let Some(txn) = current.upgrade() {
+            if !try!(self.env.env.commit_pending()) {
+                return Ok(txn);
+            }
+        }
+        let txn = Arc::new(try!(self.env.txn_begin()));
+        *current = Arc::downgrade(&txn);
+        Ok(txn)
+    }
+}
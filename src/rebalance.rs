@@ -175,9 +175,9 @@ pub fn rebalance_right<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, mut l
     debug!("sizes: {:?} {:?} {:?} sum = {:?}", right_size, left_size, middle_size, size);
 
     let mut new_left = try!(txn.alloc_page());
-    new_left.init();
+    new_left.init_with(left_child.is_counted());
     let mut new_right = try!(txn.alloc_page());
-    new_right.init();
+    new_right.init_with(left_child.is_counted());
     let mut middle = None;
     debug!("allocated {:?} and {:?}", new_left.page_offset(), new_right.page_offset());
 
@@ -196,7 +196,7 @@ pub fn rebalance_right<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, mut l
     }
 
 
-    let mut left_bytes = 24;
+    let mut left_bytes = new_left.header_size();
     let mut left_levels = [0;N_LEVELS];
     let mut right_levels = [0;N_LEVELS];
 
@@ -423,9 +423,9 @@ pub fn rebalance_left<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, mut le
     debug!("sizes: {:?} {:?} {:?} sum = {:?}", right_size, left_size, middle_size, size);
 
     let mut new_left = try!(txn.alloc_page());
-    new_left.init();
+    new_left.init_with(child_page.is_counted());
     let mut new_right = try!(txn.alloc_page());
-    new_right.init();
+    new_right.init_with(child_page.is_counted());
     let mut middle = None;
     debug!("allocated {:?} and {:?}", new_left.page_offset(), new_right.page_offset());
 
@@ -443,7 +443,7 @@ pub fn rebalance_left<R:Rng, T>(rng:&mut R, txn:&mut MutTxn<T>, page:Cow, mut le
         }
     }
 
-    let mut left_bytes = 24;
+    let mut left_bytes = new_left.header_size();
     let mut left_levels = [0;N_LEVELS];
     let mut right_levels = [0;N_LEVELS];
 
@@ -23,29 +23,53 @@
 // LMDB takes care of zombie readers, at the cost of checking a file of size linear in the number of PIDs at the beginning of every transaction. Also, doesn't work on USB sticks. More details: mdb.c, line 2606: PID locks.
 
 use std;
-use std::sync::{RwLock, RwLockReadGuard, Mutex, MutexGuard};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, Mutex, MutexGuard, Condvar};
 use std::ptr::copy_nonoverlapping;
 use std::collections::{HashSet,HashMap};
 use fs2::FileExt;
 use std::fs::{File,OpenOptions};
 use std::path::Path;
 use memmap;
+#[cfg(target_os = "linux")]
+use libc;
 
-pub const CURRENT_VERSION: u64 = 0;
+// Version 1 adds the counted and unique databases. Files of version
+// 0 are opened as they are, and upgraded when the first counted or
+// unique database is created (see `MutTxn::upgrade_version`).
+pub const CURRENT_VERSION: u64 = 1;
 
 const OFF_MAP_LENGTH:isize = 1;
 const OFF_CURRENT_FREE:isize = 2;
+// Identifier of the last committed transaction, i.e. number of
+// commits so far, in the last word of page 0.
+const OFF_TXN_ID:isize = (PAGE_SIZE as isize >> 3) - 1;
+
+// Interval between two checks for commits from other processes, in
+// milliseconds, on systems without futexes (see `Env::wait_for_commit`).
+#[cfg(not(target_os = "linux"))]
+const COMMIT_POLL_MS: u64 = 10;
 // We need a fixed page size for compatibility reasons. Most systems will have half of this, but some (SPARC) don't...
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_SIZE_16: u16 = 4096;
 pub const PAGE_SIZE_64: u64 = 4096;
 
 pub const ZERO_HEADER: isize = 24; // size of the header on page 0, in bytes.
+// Number of roots, stored between the header and the transaction
+// identifier on page 0.
+pub const ROOTS: isize = OFF_TXN_ID - (ZERO_HEADER >> 3);
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
     NotEnoughSpace,
-    Poison
+    Poison,
+    Conflict,
+    KeyTooLarge,
+    ValueTooLarge,
+    InvalidPage(u64),
+    TxnTooLarge,
+    InvalidSavepoint,
+    UnsupportedVersion(u64),
+    InvalidRoot(usize),
 }
 
 impl std::fmt::Display for Error {
@@ -53,7 +77,15 @@ impl std::fmt::Display for Error {
         match *self {
             Error::IO(ref err) => write!(f, "IO error: {}", err),
             Error::NotEnoughSpace => write!(f, "Not enough space. Try opening the environment with a larger size."),
-            Error::Poison => write!(f, "Not enough space. Try opening the environment with a larger size."),
+            Error::Poison => write!(f, "Poisoned lock or transaction, after a failure."),
+            Error::Conflict => write!(f, "Conflict with another transaction."),
+            Error::KeyTooLarge => write!(f, "Key too large."),
+            Error::ValueTooLarge => write!(f, "Value too large."),
+            Error::InvalidPage(off) => write!(f, "Invalid page: {}", off),
+            Error::TxnTooLarge => write!(f, "Transaction too large. Try committing it in smaller chunks."),
+            Error::InvalidSavepoint => write!(f, "Unknown or discarded savepoint."),
+            Error::UnsupportedVersion(v) => write!(f, "Unsupported file version: {}", v),
+            Error::InvalidRoot(num) => write!(f, "Invalid root: {}", num),
         }
     }
 }
@@ -63,15 +95,30 @@ impl std::error::Error for Error {
         match *self {
             Error::IO(ref err) => err.description(),
             Error::NotEnoughSpace => "Not enough space. Try opening the environment with a larger size.",
-            Error::Poison => "Poison error"
+            Error::Poison => "Poison error",
+            Error::Conflict => "Conflict with another transaction.",
+            Error::KeyTooLarge => "Key too large.",
+            Error::ValueTooLarge => "Value too large.",
+            Error::InvalidPage(_) => "Invalid page",
+            Error::TxnTooLarge => "Transaction too large",
+            Error::InvalidSavepoint => "Invalid savepoint",
+            Error::UnsupportedVersion(_) => "Unsupported file version",
+            Error::InvalidRoot(_) => "Invalid root",
         }
     }
     fn cause(&self) -> Option<&std::error::Error> {
         match *self {
             Error::IO(ref err) => Some(err),
             Error::NotEnoughSpace => None,
-            Error::Poison => None
-
+            Error::Poison => None,
+            Error::Conflict => None,
+            Error::KeyTooLarge => None,
+            Error::ValueTooLarge => None,
+            Error::InvalidPage(_) => None,
+            Error::TxnTooLarge => None,
+            Error::InvalidSavepoint => None,
+            Error::UnsupportedVersion(_) => None,
+            Error::InvalidRoot(_) => None,
         }
     }
 }
@@ -92,29 +139,81 @@ impl<T> From<std::sync::PoisonError<T>> for Error {
 // Why are there two synchronization mechanisms?
 // Because we would need to upgrade the read lock into a write lock, and rust has no way to do this.
 // So, we take a mutex to make sure no other mutable transaction can start,
-// and then at the time of writing, we also take the RwLock.
+// and then at the time of writing, we wait until there are no readers
+// left, and prevent new ones from starting until the header is written.
+// Readers are counted in `readers` instead of holding an RwLock guard,
+// so that read transactions can be sent to other threads.
+//
+// Writers on disjoint sets of roots don't take the mutex while they
+// run, only to commit. They are excluded from normal writers by the
+// `writers` RwLock (taken shared by them, exclusively by normal
+// writers), and allocate fresh pages from `shared.last_page`.
 
 /// Environment, required to start any transactions. Thread-safe, but opening the same database several times in the same process is not cross-platform.
 pub struct Env {
     pub length: u64,
+    pub seed: Option<u64>, // Seed of the generators of mutable transactions, if fixed.
+    pub deterministic: bool, // Whether skip list levels depend only on the bindings inserted.
     lock_file: File,
     mutable_file: File,
     mmap: memmap::Mmap,
     map: *mut u8,
-    lock: RwLock<()>, // Ensure all reads are done when sync starts.
+    readers: Mutex<Readers>, // Ensure all reads are done when sync starts.
+    readers_cond: Condvar, // Signalled when the last reader leaves, or when a commit is done.
     mutable: Mutex<()>, // Ensure only one mutable transaction can be started.
+    writers: RwLock<()>, // Exclude normal writers from writers on disjoint roots.
+    shared: Mutex<Shared>, // Allocation state shared between writers on disjoint roots.
+    savepoint_serial: std::sync::atomic::AtomicUsize, // Number of savepoints taken so far, to identify them.
+    #[cfg(test)]
+    pub fail_alloc: std::sync::atomic::AtomicUsize, // If nonzero, `alloc_page` fails when this reaches one.
+}
+
+// State shared between concurrent writers on disjoint roots.
+struct Shared {
+    writers: usize, // Number of such writers currently running.
+    roots: HashSet<isize>, // Roots declared by these writers.
+    last_page: u64, // First page not yet allocated by any writer.
+    pending_free: Vec<u64>, // Pages allocated by aborted writers, to be freed by the next commit.
+}
+
+// Readers currently running in this process.
+struct Readers {
+    count: usize,
+    writing: bool, // A commit is waiting for readers, or writing the header.
+}
+
+// Held while a commit writes the header; lets readers start again when dropped.
+struct Writing<'env>(&'env Env);
+
+impl<'env> Drop for Writing<'env> {
+    fn drop(&mut self) {
+        // Even if another thread panicked, readers must be able to start again.
+        let mut readers = self.0.readers.lock().unwrap_or_else(|e| e.into_inner());
+        readers.writing = false;
+        self.0.readers_cond.notify_all();
+    }
+}
+
+// Guard of a writer on disjoint roots.
+struct Disjoint<'env> {
+    _guard: RwLockReadGuard<'env, ()>,
+    roots: HashSet<isize>,
 }
 unsafe impl Send for Env {}
 unsafe impl Sync for Env {}
 
 pub struct Txn<'env> {
     pub env: &'env Env,
-    guard: RwLockReadGuard<'env, ()>,
+    id: u64, // identifier of the last transaction committed before this one started.
 }
 
 pub struct MutTxn<'env,T> {
     pub env: &'env Env,
     mutable: Option<MutexGuard<'env, ()>>,
+    _exclusive: Option<RwLockWriteGuard<'env, ()>>,
+    disjoint: Option<Disjoint<'env>>,
+    shared_alloc: bool, // Allocate fresh pages from `env.shared`.
+    id: u64, // identifier of the last transaction committed before this one started.
     parent:T,
     last_page: u64,
     current_list_page: Page, // current page storing the list of free pages.
@@ -123,23 +222,109 @@ pub struct MutTxn<'env,T> {
     occupied_clean_pages: HashSet<u64>, /* Offsets of pages that were allocated by this transaction, and have not been freed since. */
     free_clean_pages: Vec<u64>, /* Offsets of pages that were allocated by this transaction, and then freed. */
     free_pages: Vec<u64>, /* Offsets of old pages freed by this transaction. These were *not* allocated by this transaction. */
+    frozen_pages: HashSet<u64>, /* Clean pages reachable from a savepoint, which must not be modified in place. */
+    savepoints: Vec<Savepoint>,
+    poisoned: bool, // An operation failed midway, the pages of this transaction might be inconsistent.
+    atomic: Option<Atomic>, // Undo log of the current atomic operation.
+    images: Vec<u8>, // Contents of the pages of `Atomic::imaged`, kept allocated between operations.
+    pub stats: TxnStatistics, // Pages written by this transaction.
+    page_limit: Option<usize>, // Maximal value of `allocated_pages`, see `set_page_limit`.
+    allocated_pages: usize, // Pages taken from the file by this transaction and its parents, and not given back.
     pub roots:HashMap<isize,u64>,
 }
 
+// Undo log of an atomic operation (see `MutTxn::begin_atomic`).
+// While it runs, the clean pages allocated before it are still
+// written in place, after saving their contents the first time they
+// are loaded for writing.
+struct Atomic {
+    roots: HashMap<isize,u64>,
+    last_page: u64,
+    current_list_page: u64,
+    current_list_length: u64,
+    current_list_position: u64,
+    free_pages: usize, // length of `free_pages`.
+    free_clean_pages: usize, // lowest length of `free_clean_pages` since the beginning.
+    popped_clean_pages: Vec<u64>, // pages popped from below that length.
+    allocated: HashSet<u64>, // pages allocated by the operation.
+    shared_allocated: Vec<u64>, // pages allocated by the operation from `env.shared`.
+    released: Vec<u64>, // clean pages allocated before the operation, freed by it.
+    imaged: Vec<u64>, // clean pages allocated before the operation and loaded for writing, saved in `MutTxn::images`.
+    allocated_pages: usize,
+}
+
+/// Identifier of a savepoint in a mutable transaction, see `MutTxn::savepoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavepointId {
+    index: usize, // position in `savepoints`.
+    serial: usize, // distinguishes the savepoints taken at the same position.
+}
+
+// State of a mutable transaction at a savepoint.
+struct Savepoint {
+    serial: usize,
+    roots: HashMap<isize,u64>,
+    last_page: u64,
+    current_list_page: u64,
+    current_list_length: u64,
+    current_list_position: u64,
+    occupied_clean_pages: HashSet<u64>,
+    free_clean_pages: Vec<u64>,
+    free_pages: usize, // length of `free_pages`.
+    frozen_pages: HashSet<u64>,
+    poisoned: bool,
+    allocated_pages: usize,
+}
+
 impl<'env> Drop for Txn<'env> {
     fn drop(&mut self) {
-        self.env.lock_file.unlock().unwrap();
-        *self.guard;
+        let mut readers = self.env.readers.lock().unwrap();
+        readers.count -= 1;
+        // The file lock is shared by all readers of this process.
+        if readers.count == 0 {
+            self.env.lock_file.unlock().unwrap();
+            self.env.readers_cond.notify_all();
+        }
     }
 }
 impl<'env,T> Drop for MutTxn<'env,T> {
     fn drop(&mut self) {
         debug!("dropping transaction");
-        self.env.mutable_file.unlock().unwrap();
+        // Only the outermost transaction holds the locks.
         if let Some(ref mut guard) = self.mutable {
+            self.env.mutable_file.unlock().unwrap();
             debug!("dropping guard");
             **guard
         }
+        // Aborting a child transaction leaves its parent as it was:
+        // the child only changed its own copies of `last_page` and of
+        // the position in the list of free pages. Writers on disjoint
+        // roots, and their children, allocate from `env.shared`
+        // instead, and must give their pages back.
+        if self.shared_alloc {
+            let mut shared = self.env.shared.lock().unwrap();
+            // If this transaction was not committed, the pages it
+            // allocated might be beyond the last page written by
+            // another commit: give them back.
+            let mut allocated: Vec<u64> = self.occupied_clean_pages.iter().cloned().collect();
+            allocated.extend(self.free_clean_pages.iter());
+            // Frozen pages freed after their savepoint are in `free_pages`.
+            allocated.extend(self.frozen_pages.iter());
+            // Sorted, so that the list of free pages doesn't depend on
+            // the order of a HashSet.
+            allocated.sort();
+            allocated.dedup();
+            shared.pending_free.extend(allocated);
+            if let Some(ref disjoint) = self.disjoint {
+                for r in disjoint.roots.iter() {
+                    shared.roots.remove(r);
+                }
+                shared.writers -= 1;
+                if shared.writers == 0 {
+                    self.env.mutable_file.unlock().unwrap();
+                }
+            }
+        }
     }
 }
 
@@ -152,6 +337,41 @@ pub struct Statistics {
     pub reference_counts: HashMap<u64,u64>
 }
 
+/// Counters of the pages written by a mutable transaction, see
+/// `MutTxn::stats`. Operations cancelled by a rollback or an error are
+/// counted too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TxnStatistics {
+    /// Pages allocated at the end of the file.
+    pub fresh_pages: u64,
+    /// Pages allocated from the list of pages freed by previous transactions.
+    pub free_list_pages: u64,
+    /// Pages allocated and freed by this transaction, then allocated again.
+    pub reused_clean_pages: u64,
+    /// Pages copied to a new page before being modified.
+    pub copied_pages: u64,
+    /// Pages freed.
+    pub freed_pages: u64,
+    /// Reference counts of pages or values changed, whether
+    /// incremented, decremented or removed. Pages and values are
+    /// reference-counted once they are shared by forked databases.
+    pub rc_updates: u64,
+    /// Pages allocated to store large values.
+    pub value_pages: u64,
+}
+
+impl TxnStatistics {
+    fn add(&mut self, other: &TxnStatistics) {
+        self.fresh_pages += other.fresh_pages;
+        self.free_list_pages += other.free_list_pages;
+        self.reused_clean_pages += other.reused_clean_pages;
+        self.copied_pages += other.copied_pages;
+        self.freed_pages += other.freed_pages;
+        self.rc_updates += other.rc_updates;
+        self.value_pages += other.value_pages;
+    }
+}
+
 
 impl Env {
     /// Initialize environment. log_length must be at least log(PAGE_SIZE)
@@ -178,73 +398,269 @@ impl Env {
         let map = mmap.mut_ptr();
         if !db_exists {
             unsafe {
+                // New files are of version 0, until they contain
+                // pages that older versions cannot read.
                 std::ptr::write_bytes(map, 0, PAGE_SIZE);
-                *(map as *mut u64) = CURRENT_VERSION.to_le();
             }
         } else {
-            assert!(unsafe { u64::from_le(*(map as *const u64)) == CURRENT_VERSION })
+            let version = unsafe { u64::from_le(*(map as *const u64)) };
+            if version > CURRENT_VERSION {
+                return Err(Error::UnsupportedVersion(version))
+            }
         }
         let env = Env {
             length: length,
+            seed: None,
+            deterministic: false,
             mmap: mmap,
             map: map,
             lock_file: lock_file,
             mutable_file: mutable_file,
-            lock: RwLock::new(()),
+            readers: Mutex::new(Readers {
+                count: 0,
+                writing: false,
+            }),
+            readers_cond: Condvar::new(),
             mutable: Mutex::new(()),
+            writers: RwLock::new(()),
+            shared: Mutex::new(Shared {
+                writers: 0,
+                roots: HashSet::new(),
+                last_page: 0,
+                pending_free: Vec::new(),
+            }),
+            savepoint_serial: std::sync::atomic::AtomicUsize::new(0),
+            #[cfg(test)]
+            fail_alloc: std::sync::atomic::AtomicUsize::new(0),
         };
         Ok(env)
     }
     /// Start a read-only transaction.
     pub fn txn_begin<'env>(&'env self) -> Result<Txn<'env>,Error> {
-        let read = try!(self.lock.read());
-        try!(self.lock_file.lock_shared());
+        let mut readers = try!(self.readers.lock());
+        while readers.writing {
+            readers = try!(self.readers_cond.wait(readers));
+        }
+        if readers.count == 0 {
+            try!(self.lock_file.lock_shared());
+        }
+        readers.count += 1;
         Ok(Txn {
             env: self,
-            guard: read,
+            id: self.last_txn_id(),
         })
     }
 
+    /// Whether a commit is waiting for the readers of this process
+    /// to finish, in which case new read transactions will block.
+    pub fn commit_pending(&self) -> Result<bool, Error> {
+        Ok(try!(self.readers.lock()).writing)
+    }
+
+    /// Wait until a transaction is committed after the one with
+    /// identifier `since` (see `Txn::id`), or until `timeout`
+    /// expires. Returns the identifier of the last committed
+    /// transaction, or `None` on timeout.
+    ///
+    /// On Linux, waiting threads sleep on a futex on the transaction
+    /// identifier in the header, which is shared by all the processes
+    /// mapping the file, and are woken by the commits of any of
+    /// them. Elsewhere, commits from this process wake the waiting
+    /// threads immediately, and commits from other processes are seen
+    /// through the shared map, which is checked every few milliseconds.
+    pub fn wait_for_commit(&self, since: u64, timeout: std::time::Duration) -> Result<Option<u64>, Error> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            // Read the word to wait on before the identifier, so that
+            // a commit between the two isn't missed.
+            let word = unsafe { std::ptr::read_volatile(self.txn_id_word()) };
+            let id = self.last_txn_id();
+            if id > since {
+                return Ok(Some(id));
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            try!(self.wait_txn_id(word, deadline - now))
+        }
+    }
+
+    // First 32 bits of the transaction identifier, which change with
+    // each commit.
+    fn txn_id_word(&self) -> *mut u32 {
+        unsafe { (self.map as *mut u64).offset(OFF_TXN_ID) as *mut u32 }
+    }
+
+    // Sleep until the first word of the transaction identifier is not
+    // `word` anymore, or for at most `timeout`.
+    #[cfg(target_os = "linux")]
+    fn wait_txn_id(&self, word: u32, timeout: std::time::Duration) -> Result<(), Error> {
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        let result = unsafe {
+            libc::syscall(libc::SYS_futex, self.txn_id_word(), libc::FUTEX_WAIT, word, &timeout as *const libc::timespec)
+        };
+        if result < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EINTR) | Some(libc::ETIMEDOUT) => {}
+                _ => return Err(Error::IO(err))
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn wait_txn_id(&self, _word: u32, timeout: std::time::Duration) -> Result<(), Error> {
+        let readers = try!(self.readers.lock());
+        let wait = std::cmp::min(timeout, std::time::Duration::from_millis(COMMIT_POLL_MS));
+        try!(self.readers_cond.wait_timeout(readers, wait));
+        Ok(())
+    }
+
+    // Wake the threads of all processes waiting in `wait_for_commit`.
+    #[cfg(target_os = "linux")]
+    fn wake_txn_id(&self) {
+        unsafe {
+            libc::syscall(libc::SYS_futex, self.txn_id_word(), libc::FUTEX_WAKE, std::i32::MAX);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn wake_txn_id(&self) {}
+
+    // Page 0 is the header, and cannot be the root of a database.
+    fn check_page(&self, off: u64) -> Result<(), Error> {
+        if off == 0 || off & (PAGE_SIZE_64 - 1) != 0 || off >= self.length {
+            Err(Error::InvalidPage(off))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn last_txn_id(&self) -> u64 {
+        unsafe { u64::from_le(*((self.map as *const u64).offset(OFF_TXN_ID))) }
+    }
+
+    // Read the first free page and the current page of the free list from the header.
+    unsafe fn read_header(&self) -> (u64, Page, u64) {
+        let last_page = u64::from_le(*((self.map as *const u64).offset(OFF_MAP_LENGTH)));
+        let current_list_page = u64::from_le(*((self.map as *const u64).offset(OFF_CURRENT_FREE)));
+        debug!("map header = {:?}, {:?}", last_page ,current_list_page);
+        let current_list_page = Page {
+            data: self.map.offset(current_list_page as isize),
+            offset: current_list_page,
+        };
+        let current_list_length = if current_list_page.offset == 0 {
+            0
+        } else {
+            u64::from_le(*((current_list_page.data as *const u64).offset(1)))
+        };
+        (std::cmp::max(last_page, PAGE_SIZE as u64), current_list_page, current_list_length)
+    }
+
     /// Start a mutable transaction. Mutable transactions that go out of scope are automatically aborted.
     pub fn mut_txn_begin<'env>(&'env self) -> Result<MutTxn<'env,()>, Error> {
         unsafe {
-            let last_page = u64::from_le(*((self.map as *const u64).offset(OFF_MAP_LENGTH)));
-            let current_list_page = u64::from_le(*((self.map as *const u64).offset(OFF_CURRENT_FREE)));
-
-            debug!("map header = {:?}, {:?}", last_page ,current_list_page);
+            let exclusive = try!(self.writers.write());
             let guard = try!(self.mutable.lock());
             debug!("taking file lock");
             try!(self.mutable_file.lock_exclusive());
             debug!("lock ok");
-            let current_list_page = Page {
-                data: self.map.offset(current_list_page as isize),
-                offset: current_list_page,
-            };
-            let current_list_length = if current_list_page.offset == 0 {
-                0
-            } else {
-                u64::from_le(*((current_list_page.data as *const u64).offset(1)))
-            };
+            let (last_page, current_list_page, current_list_length) = self.read_header();
+            // Writers on disjoint roots may have allocated pages
+            // that are not yet recorded in the header.
+            let last_page = std::cmp::max(last_page, try!(self.shared.lock()).last_page);
             Ok(MutTxn {
                 env: self,
                 mutable: Some(guard),
+                _exclusive: Some(exclusive),
+                disjoint: None,
+                shared_alloc: false,
+                id: self.last_txn_id(),
                 parent:(),
-                last_page: if last_page == 0 {
-                    PAGE_SIZE as u64
-                } else {
-                    last_page
-                },
+                last_page: last_page,
                 current_list_page: current_list_page,
                 current_list_length: current_list_length,
                 current_list_position: current_list_length, /* position of the word immediately after the top. */
                 occupied_clean_pages: HashSet::new(),
                 free_clean_pages: Vec::new(),
                 free_pages: Vec::new(),
+                frozen_pages: HashSet::new(),
+                savepoints: Vec::new(),
+                poisoned: false,
+                atomic: None,
+            images: Vec::new(),
+                stats: TxnStatistics::default(),
+                page_limit: None,
+                allocated_pages: 0,
                 roots: HashMap::new(),
             })
         }
     }
 
+    /// Start a mutable transaction that may only change the given
+    /// roots. Such transactions run concurrently with each other, but
+    /// not with the ones started by `mut_txn_begin`, and are
+    /// serialized only when committing. Returns `Error::Conflict` if
+    /// one of the roots is already used by another writer.
+    ///
+    /// These transactions never reuse pages from the list of free
+    /// pages, and only coordinate with the writers of the same
+    /// process.
+    ///
+    /// A commit records as allocated all the fresh pages taken so far,
+    /// including those of the writers still running. These pages are
+    /// given back when their writer aborts, but if the process crashes
+    /// first, they are leaked: they are neither in use nor in the list
+    /// of free pages of the file.
+    pub fn mut_txn_begin_roots<'env>(&'env self, roots: &[isize]) -> Result<MutTxn<'env,()>, Error> {
+        let guard = try!(self.writers.read());
+        let mut shared = try!(self.shared.lock());
+        if roots.iter().any(|r| shared.roots.contains(r)) {
+            return Err(Error::Conflict)
+        }
+        if shared.writers == 0 {
+            // Exclude the writers of other processes.
+            try!(self.mutable_file.lock_exclusive());
+        }
+        shared.writers += 1;
+        shared.roots.extend(roots.iter());
+        let (last_page, current_list_page, current_list_length) = unsafe { self.read_header() };
+        shared.last_page = std::cmp::max(shared.last_page, last_page);
+        Ok(MutTxn {
+            env: self,
+            mutable: None,
+            _exclusive: None,
+            disjoint: Some(Disjoint {
+                _guard: guard,
+                roots: roots.iter().cloned().collect(),
+            }),
+            shared_alloc: true,
+            id: self.last_txn_id(),
+            parent: (),
+            last_page: shared.last_page,
+            current_list_page: current_list_page,
+            current_list_length: current_list_length,
+            current_list_position: 0, // never pop from the free list.
+            occupied_clean_pages: HashSet::new(),
+            free_clean_pages: Vec::new(),
+            free_pages: Vec::new(),
+            frozen_pages: HashSet::new(),
+            savepoints: Vec::new(),
+            poisoned: false,
+            atomic: None,
+            images: Vec::new(),
+            stats: TxnStatistics::default(),
+            page_limit: None,
+            allocated_pages: 0,
+            roots: HashMap::new(),
+        })
+    }
+
     /// Compute statistics about pages. This is a potentially costlty operation, as we need to go through all bookkeeping pages.
     pub fn statistics(&self) -> Statistics {
         unsafe {
@@ -303,7 +719,20 @@ impl MutPage {
 
 pub unsafe fn free<T>(txn: &mut MutTxn<T>, offset: u64) {
     debug!("transaction::free page: {:?}", offset);
-    if txn.occupied_clean_pages.remove(&offset) {
+    txn.stats.freed_pages += 1;
+    let before_operation = if let Some(ref atomic) = txn.atomic {
+        !atomic.allocated.contains(&offset)
+    } else {
+        false
+    };
+    if (before_operation || txn.frozen_pages.contains(&offset)) && txn.occupied_clean_pages.remove(&offset) {
+        // Still used by a savepoint, or by the state before the
+        // current atomic operation: free it at commit.
+        if before_operation {
+            txn.atomic.as_mut().unwrap().released.push(offset)
+        }
+        txn.free_pages.push(offset)
+    } else if txn.occupied_clean_pages.remove(&offset) {
         txn.free_clean_pages.push(offset);
     } else {
         // Else, register it for freeing (we cannot reuse it in this transaction).
@@ -313,9 +742,17 @@ pub unsafe fn free<T>(txn: &mut MutTxn<T>, offset: u64) {
 
 impl<'env> Txn<'env> {
     /// Find the appropriate map segment
+    /// Return `Error::InvalidPage` if `off` is not the offset of a page of this environment.
+    /// Only the roots given to the public functions are checked, the
+    /// pages they point to are trusted.
+    pub fn check_page(&self, off: u64) -> Result<(), Error> {
+        self.env.check_page(off)
+    }
+    /// Pages are not validated (see `check_page`), but a page never
+    /// extends past the end of the map.
     pub fn load_page(&self, off: u64) -> Page {
         debug!("load_page: off={:?}, length = {:?}", off, self.env.length);
-        assert!(off < self.env.length);
+        assert!(off & (PAGE_SIZE_64 - 1) == 0 && off < self.env.length, "invalid page {:?}", off);
         unsafe {
             Page {
                 data: self.env.map.offset(off as isize),
@@ -323,12 +760,34 @@ impl<'env> Txn<'env> {
             }
         }
     }
+    /// Identifier of the last transaction committed before this one started.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
     pub fn root(&self,num:isize) -> u64 {
-        assert!(ZERO_HEADER + ((num+1)<<3) < (PAGE_SIZE as isize));
+        assert!(num >= 0 && num < ROOTS, "invalid root {:?}", num);
         unsafe {
             u64::from_le(*((self.env.map.offset(ZERO_HEADER) as *const u64).offset(num)))
         }
     }
+
+    /// Turn this transaction into a mutable one, keeping the same
+    /// snapshot. This fails with `Error::Conflict` if another
+    /// transaction was committed since this one started.
+    pub fn upgrade(self) -> Result<MutTxn<'env,()>, Error> {
+        let env = self.env;
+        let id = self.id;
+        // Release the read lock before waiting for writers, since
+        // they might need it to commit. If one of them commits in
+        // the meantime, the identifier changes.
+        std::mem::drop(self);
+        let txn = try!(env.mut_txn_begin());
+        if txn.id == id {
+            Ok(txn)
+        } else {
+            Err(Error::Conflict)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -339,30 +798,45 @@ pub enum Cow {
 
 impl<'env,T> MutTxn<'env,T> {
     pub fn mut_txn_begin<'txn>(&'txn mut self) -> Result<MutTxn<'env,&'txn mut MutTxn<'env,T>>, Error> {
-        unsafe {
-            let mut txn = MutTxn {
-                env: self.env,
-                mutable: None,
-                parent: std::mem::uninitialized(),
-                last_page: self.last_page,
-                current_list_page: Page { data:self.current_list_page.data,
-                                          offset: self.current_list_page.offset },
-                current_list_length: self.current_list_length,
-                current_list_position: self.current_list_position,
-                occupied_clean_pages: HashSet::new(),
-                free_clean_pages: Vec::new(),
-                free_pages: Vec::new(),
-                roots:self.roots.clone(),
-                //reference_counts:self.reference_counts
-            };
-            txn.parent = self;
-            Ok(txn)
-        }
+        try!(self.check_poison());
+        let current_list_page = Page { data:self.current_list_page.data,
+                                       offset: self.current_list_page.offset };
+        Ok(MutTxn {
+            env: self.env,
+            mutable: None,
+            _exclusive: None,
+            disjoint: None,
+            shared_alloc: self.shared_alloc,
+            id: self.id,
+            last_page: self.last_page,
+            current_list_page: current_list_page,
+            current_list_length: self.current_list_length,
+            current_list_position: self.current_list_position,
+            occupied_clean_pages: HashSet::new(),
+            free_clean_pages: Vec::new(),
+            free_pages: Vec::new(),
+            frozen_pages: HashSet::new(),
+            savepoints: Vec::new(),
+            poisoned: false,
+            atomic: None,
+            images: Vec::new(),
+            stats: TxnStatistics::default(),
+            page_limit: self.page_limit,
+            allocated_pages: self.allocated_pages,
+            roots:self.roots.clone(),
+            parent: self,
+        })
+    }
+    /// Return `Error::InvalidPage` if `off` is not the offset of a page of this environment.
+    /// Only the roots given to the public functions are checked, the
+    /// pages they point to are trusted.
+    pub fn check_page(&self, off: u64) -> Result<(), Error> {
+        self.env.check_page(off)
     }
+    /// Pages are not validated (see `check_page`), but a page never
+    /// extends past the end of the map.
     pub fn load_page(&self, off: u64) -> Page {
-        if off >= self.env.length {
-            panic!("{:?} >= {:?}", off,self.env.length)
-        }
+        assert!(off & (PAGE_SIZE_64 - 1) == 0 && off < self.env.length, "invalid page {:?}", off);
         unsafe {
             Page {
                 data: self.env.map.offset(off as isize),
@@ -370,25 +844,228 @@ impl<'env,T> MutTxn<'env,T> {
             }
         }
     }
+    /// Identifier of the last transaction committed before this one started.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
     pub fn root(&self, num:isize) -> u64 {
         if let Some(root) = self.roots.get(&num) {
             *root
         } else {
-            assert!(ZERO_HEADER + ((num+1)<<3) < (PAGE_SIZE as isize));
+            assert!(num >= 0 && num < ROOTS, "invalid root {:?}", num);
             unsafe {
                 u64::from_le(*((self.env.map.offset(ZERO_HEADER) as *const u64).offset(num as isize)))
             }
         }
     }
     pub fn set_root(&mut self, num:isize, value:u64) {
+        assert!(num >= 0 && num < ROOTS, "invalid root {:?}", num);
         self.roots.insert(num,value);
     }
+
+    /// Raise the version of the file to `CURRENT_VERSION`, before
+    /// writing pages that older versions cannot read. The header is
+    /// written immediately, and flushed by the next commit, even if
+    /// this transaction is aborted.
+    pub fn upgrade_version(&mut self) {
+        unsafe {
+            let version = self.env.map as *mut u64;
+            if u64::from_le(*version) < CURRENT_VERSION {
+                *version = CURRENT_VERSION.to_le()
+            }
+        }
+    }
+
+    /// Mark this transaction as inconsistent, after an operation
+    /// failed midway. It cannot be committed anymore, but can still
+    /// be rolled back to a savepoint taken before the failure.
+    pub fn poison(&mut self) {
+        self.poisoned = true
+    }
+
+    /// Return `Error::Poison` if this transaction was poisoned.
+    pub fn check_poison(&self) -> Result<(), Error> {
+        if self.poisoned {
+            Err(Error::Poison)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start an atomic operation: until `end_atomic` is called, the
+    /// changes can be cancelled with `abort_atomic`. Operations
+    /// cannot be nested, and no savepoint can be taken or rolled back
+    /// to during an operation.
+    pub fn begin_atomic(&mut self) {
+        assert!(self.atomic.is_none());
+        self.images.clear();
+        self.atomic = Some(Atomic {
+            roots: self.roots.clone(),
+            last_page: self.last_page,
+            current_list_page: self.current_list_page.offset,
+            current_list_length: self.current_list_length,
+            current_list_position: self.current_list_position,
+            free_pages: self.free_pages.len(),
+            free_clean_pages: self.free_clean_pages.len(),
+            popped_clean_pages: Vec::new(),
+            allocated: HashSet::new(),
+            shared_allocated: Vec::new(),
+            released: Vec::new(),
+            imaged: Vec::new(),
+            allocated_pages: self.allocated_pages,
+        })
+    }
+
+    /// Number of pages taken from the file (from the list of free
+    /// pages, or from its end) by this transaction and its parents,
+    /// and not given back by a rollback.
+    pub fn dirty_pages(&self) -> usize {
+        self.allocated_pages
+    }
+
+    /// Make `alloc_page` fail with `Error::TxnTooLarge` instead of
+    /// growing `dirty_pages` beyond `limit`. Child transactions
+    /// started after this inherit the limit.
+    pub fn set_page_limit(&mut self, limit: Option<usize>) {
+        self.page_limit = limit
+    }
+
+    /// Whether an atomic operation is running.
+    pub fn in_atomic(&self) -> bool {
+        self.atomic.is_some()
+    }
+
+    /// Keep the changes of the current atomic operation.
+    pub fn end_atomic(&mut self) {
+        if let Some(atomic) = self.atomic.take() {
+            // The clean pages freed by the operation can now be reused.
+            let released: HashSet<u64> = atomic.released.into_iter().filter(|p| !self.frozen_pages.contains(p)).collect();
+            if !released.is_empty() {
+                let freed = self.free_pages.split_off(atomic.free_pages);
+                for p in freed {
+                    if released.contains(&p) {
+                        self.free_clean_pages.push(p)
+                    } else {
+                        self.free_pages.push(p)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Cancel the changes of the current atomic operation, bringing
+    /// the allocator and the roots back to their state at
+    /// `begin_atomic`.
+    pub fn abort_atomic(&mut self) {
+        if let Some(atomic) = self.atomic.take() {
+            for (p, image) in atomic.imaged.iter().zip(self.images.chunks(PAGE_SIZE)) {
+                unsafe { copy_nonoverlapping(image.as_ptr(), self.env.map.offset(*p as isize), PAGE_SIZE) }
+            }
+            for p in atomic.allocated.iter() {
+                self.occupied_clean_pages.remove(p);
+            }
+            self.occupied_clean_pages.extend(atomic.released);
+            self.free_clean_pages.truncate(atomic.free_clean_pages);
+            self.free_clean_pages.extend(atomic.popped_clean_pages.into_iter().rev());
+            // Pages taken from `env.shared` cannot be given back there.
+            self.allocated_pages = atomic.allocated_pages + atomic.shared_allocated.len();
+            self.free_clean_pages.extend(atomic.shared_allocated);
+            self.free_pages.truncate(atomic.free_pages);
+            self.roots = atomic.roots;
+            self.last_page = atomic.last_page;
+            self.current_list_page = Page {
+                data: unsafe { self.env.map.offset(atomic.current_list_page as isize) },
+                offset: atomic.current_list_page,
+            };
+            self.current_list_length = atomic.current_list_length;
+            self.current_list_position = atomic.current_list_position;
+        }
+    }
+
+    /// Record the current state of this transaction, so that it can
+    /// be restored later with `rollback_to`. The pages allocated so
+    /// far are not modified in place anymore, but copied on write.
+    pub fn savepoint(&mut self) -> SavepointId {
+        self.frozen_pages.extend(self.occupied_clean_pages.iter());
+        let serial = self.env.savepoint_serial.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.savepoints.push(Savepoint {
+            serial: serial,
+            roots: self.roots.clone(),
+            last_page: self.last_page,
+            current_list_page: self.current_list_page.offset,
+            current_list_length: self.current_list_length,
+            current_list_position: self.current_list_position,
+            occupied_clean_pages: self.occupied_clean_pages.clone(),
+            free_clean_pages: self.free_clean_pages.clone(),
+            free_pages: self.free_pages.len(),
+            frozen_pages: self.frozen_pages.clone(),
+            poisoned: self.poisoned,
+            allocated_pages: self.allocated_pages,
+        });
+        SavepointId {
+            index: self.savepoints.len() - 1,
+            serial: serial,
+        }
+    }
+
+    /// Cancel all the changes made since savepoint `id`. The
+    /// savepoints taken after `id` are discarded, while `id` itself
+    /// can be rolled back to again. Returns
+    /// `Error::InvalidSavepoint` if `id` was discarded, or taken in
+    /// another transaction.
+    pub fn rollback_to(&mut self, id: SavepointId) -> Result<(), Error> {
+        if self.savepoints.get(id.index).map(|sp| sp.serial != id.serial).unwrap_or(true) {
+            return Err(Error::InvalidSavepoint)
+        }
+        self.savepoints.truncate(id.index + 1);
+        let sp = self.savepoints.pop().unwrap();
+        if self.shared_alloc {
+            // Fresh pages come from `env.shared`, and cannot be given
+            // back there: keep the ones allocated after the
+            // savepoint for reuse in this transaction.
+            let mut before: HashSet<u64> = sp.frozen_pages.clone();
+            before.extend(sp.free_clean_pages.iter());
+            let mut after: HashSet<u64> = self.occupied_clean_pages.iter().cloned().collect();
+            after.extend(self.free_clean_pages.iter());
+            after.extend(self.frozen_pages.iter());
+            let mut released: Vec<u64> = after.difference(&before).cloned().collect();
+            released.sort();
+            self.allocated_pages = sp.allocated_pages + released.len();
+            self.free_clean_pages = sp.free_clean_pages.clone();
+            self.free_clean_pages.extend(released);
+        } else {
+            // Restoring `last_page` and the position in the list of
+            // free pages is enough to release the other pages.
+            self.allocated_pages = sp.allocated_pages;
+            self.free_clean_pages = sp.free_clean_pages.clone();
+        }
+        self.roots = sp.roots.clone();
+        self.last_page = sp.last_page;
+        self.current_list_page = Page {
+            data: unsafe { self.env.map.offset(sp.current_list_page as isize) },
+            offset: sp.current_list_page,
+        };
+        self.current_list_length = sp.current_list_length;
+        self.current_list_position = sp.current_list_position;
+        self.occupied_clean_pages = sp.occupied_clean_pages.clone();
+        self.free_pages.truncate(sp.free_pages);
+        self.frozen_pages = sp.frozen_pages.clone();
+        self.poisoned = sp.poisoned;
+        self.savepoints.push(sp);
+        Ok(())
+    }
     pub fn load_cow_page(&mut self, off: u64) -> Cow {
         debug!("transaction::load_mut_page: {:?} {:?}",
                off,
                self.occupied_clean_pages);
         assert!(off < self.env.length);
-        if off != 0 && self.occupied_clean_pages.contains(&off) {
+        if off != 0 && self.occupied_clean_pages.contains(&off) && !self.frozen_pages.contains(&off) {
+            if let Some(ref mut atomic) = self.atomic {
+                if !atomic.allocated.contains(&off) && !atomic.imaged.contains(&off) {
+                    atomic.imaged.push(off);
+                    self.images.extend_from_slice(unsafe { std::slice::from_raw_parts(self.env.map.offset(off as isize), PAGE_SIZE) });
+                }
+            }
             unsafe {
                 Cow::MutPage(MutPage {
                     data: self.env.map.offset(off as isize),
@@ -447,37 +1124,84 @@ impl<'env,T> MutTxn<'env,T> {
     /// Allocate a single page.
     pub fn alloc_page(&mut self) -> Result<MutPage,Error> {
         debug!("alloc page");
+        #[cfg(test)]
+        {
+            let n = self.env.fail_alloc.load(std::sync::atomic::Ordering::SeqCst);
+            if n > 0 {
+                self.env.fail_alloc.store(n - 1, std::sync::atomic::Ordering::SeqCst);
+                if n == 1 {
+                    return Err(Error::NotEnoughSpace)
+                }
+            }
+        }
         // If we have allocated and freed a page in this transaction, use it first.
         if let Some(page) = self.free_clean_pages.pop() {
             debug!("clean page reuse:{}", page);
+            self.stats.reused_clean_pages += 1;
             self.occupied_clean_pages.insert(page);
+            if let Some(ref mut atomic) = self.atomic {
+                atomic.allocated.insert(page);
+                if self.free_clean_pages.len() < atomic.free_clean_pages {
+                    atomic.free_clean_pages = self.free_clean_pages.len();
+                    atomic.popped_clean_pages.push(page)
+                }
+            }
             Ok(MutPage {
                 data: unsafe { self.env.map.offset(page as isize) },
                 offset: page,
             })
         } else {
-            // Else, if there are free pages, take one.
-            if let Some(page) = self.free_pages_pop() {
+            if let Some(limit) = self.page_limit {
+                if self.allocated_pages >= limit {
+                    return Err(Error::TxnTooLarge)
+                }
+            }
+            // Else, if there are free pages, take one (writers on
+            // disjoint roots don't own the list of free pages).
+            if let Some(page) = if self.shared_alloc { None } else { self.free_pages_pop() } {
                 debug!("using an old free page: {}", page);
+                self.stats.free_list_pages += 1;
+                self.allocated_pages += 1;
                 self.occupied_clean_pages.insert(page);
+                if let Some(ref mut atomic) = self.atomic {
+                    atomic.allocated.insert(page);
+                }
                 Ok(MutPage {
                     data: unsafe { self.env.map.offset(page as isize) },
                     offset: page,
                 })
             } else {
                 // Else, allocate in the free space.
-                let last = self.last_page;
-                debug!("eating the free space: {}", last);
-                if self.last_page + PAGE_SIZE_64 < self.env.length {
-                    self.last_page += PAGE_SIZE_64;
-                    self.occupied_clean_pages.insert(last);
-                    Ok(MutPage {
-                        data: unsafe { self.env.map.offset(last as isize) },
-                        offset: last,
-                    })
+                let last = if self.shared_alloc {
+                    let mut shared = try!(self.env.shared.lock());
+                    if shared.last_page + PAGE_SIZE_64 < self.env.length {
+                        shared.last_page += PAGE_SIZE_64;
+                        shared.last_page - PAGE_SIZE_64
+                    } else {
+                        return Err(Error::NotEnoughSpace)
+                    }
                 } else {
-                    Err(Error::NotEnoughSpace)
+                    if self.last_page + PAGE_SIZE_64 < self.env.length {
+                        self.last_page += PAGE_SIZE_64;
+                        self.last_page - PAGE_SIZE_64
+                    } else {
+                        return Err(Error::NotEnoughSpace)
+                    }
+                };
+                debug!("eating the free space: {}", last);
+                self.stats.fresh_pages += 1;
+                self.allocated_pages += 1;
+                self.occupied_clean_pages.insert(last);
+                if let Some(ref mut atomic) = self.atomic {
+                    atomic.allocated.insert(last);
+                    if self.shared_alloc {
+                        atomic.shared_allocated.push(last)
+                    }
                 }
+                Ok(MutPage {
+                    data: unsafe { self.env.map.offset(last as isize) },
+                    offset: last,
+                })
             }
         }
     }
@@ -489,15 +1213,30 @@ pub trait Commit {
 
 impl<'a,'env,T> Commit for MutTxn<'env,&'a mut MutTxn<'env,T>> {
     fn commit(&mut self)->Result<(),Error> {
-
+        try!(self.check_poison());
         self.parent.last_page = self.last_page;
         self.parent.current_list_page = Page { offset:self.current_list_page.offset,
                                                data:self.current_list_page.data };
         self.parent.current_list_length = self.current_list_length;
         self.parent.current_list_position = self.current_list_position;
-        self.parent.occupied_clean_pages.extend(self.occupied_clean_pages.iter());
-        self.parent.free_clean_pages.extend(self.free_clean_pages.iter());
-        self.parent.free_pages.extend(self.free_pages.iter());
+        // The pages are moved to the parent, so that dropping the
+        // child after this does not release them.
+        self.parent.occupied_clean_pages.extend(self.occupied_clean_pages.drain());
+        self.parent.free_clean_pages.append(&mut self.free_clean_pages);
+        for p in self.free_pages.drain(..) {
+            // Pages allocated by the child and freed after one of its
+            // savepoints can be reused right away by the parent.
+            if self.frozen_pages.contains(&p) {
+                self.parent.free_clean_pages.push(p)
+            } else {
+                self.parent.free_pages.push(p)
+            }
+        }
+        self.frozen_pages.clear();
+        self.savepoints.clear();
+        self.parent.stats.add(&self.stats);
+        self.parent.allocated_pages = self.allocated_pages;
+        self.stats = TxnStatistics::default();
         for (u,v) in self.roots.iter() {
             self.parent.roots.insert(*u,*v);
         }
@@ -508,6 +1247,20 @@ impl<'a,'env,T> Commit for MutTxn<'env,&'a mut MutTxn<'env,T>> {
 impl<'env> Commit for MutTxn<'env,()> {
     /// Commit a transaction. This is guaranteed to be atomic: either the commit succeeds, and all the changes made during the transaction are written to disk. Or the commit doesn't succeed, and we're back to the state just before starting the transaction.
     fn commit(&mut self) -> Result<(), Error> {
+        self.commit_if_(None)
+    }
+}
+
+impl<'env> MutTxn<'env,()> {
+    /// Commit this transaction only if the identifier of the last
+    /// committed transaction is still `expected`, for instance the
+    /// `id` of a read transaction on which the changes were
+    /// computed. Else, return `Error::Conflict`.
+    pub fn commit_if(&mut self, expected: u64) -> Result<(), Error> {
+        self.commit_if_(Some(expected))
+    }
+
+    fn commit_if_(&mut self, expected: Option<u64>) -> Result<(), Error> {
         // Tasks:
         // - allocate new pages (copy-on-write) to write the new list of free pages, including edited "stack pages".
         //
@@ -515,9 +1268,46 @@ impl<'env> Commit for MutTxn<'env,()> {
         // - write user data
         //
         // everything can be sync'ed at any time, except that the first page needs to be sync'ed last.
+        try!(self.check_poison());
+        let _guard = if let Some(ref disjoint) = self.disjoint {
+            if self.roots.keys().any(|r| !disjoint.roots.contains(r)) {
+                return Err(Error::Conflict)
+            }
+            // Other writers on disjoint roots might have committed
+            // since this transaction started: serialize commits, and
+            // start from the current list of free pages.
+            let guard = try!(self.env.mutable.lock());
+            if expected.map(|e| e != self.env.last_txn_id()).unwrap_or(false) {
+                return Err(Error::Conflict)
+            }
+            let (_, current_list_page, current_list_length) = unsafe { self.env.read_header() };
+            self.current_list_page = current_list_page;
+            self.current_list_length = current_list_length;
+            self.current_list_position = current_list_length;
+            Some(guard)
+        } else {
+            // We hold the writers lock, no other commit can happen.
+            if expected.map(|e| e != self.env.last_txn_id()).unwrap_or(false) {
+                return Err(Error::Conflict)
+            }
+            None
+        };
         unsafe {
-            // Copy the current bookkeeping page to a newly allocated page.
-            let mut current_page = try!(self.alloc_page());
+            // Copy the current bookkeeping page to a newly allocated
+            // page. Committing must not fail because of the limit.
+            let page_limit = self.page_limit.take();
+            let current_page = self.alloc_page();
+            self.page_limit = page_limit;
+            let mut current_page = try!(current_page);
+            // The pages given back by aborted writers are removed from
+            // `shared` only once the commit has succeeded, so that they
+            // are not lost if it fails. The loop below consumes them,
+            // so that retrying the commit doesn't free them twice.
+            let n_pending = {
+                let shared = try!(self.env.shared.lock());
+                self.free_pages.extend(shared.pending_free.iter());
+                shared.pending_free.len()
+            };
             if self.current_list_page.offset != 0 {
                 // If there was at least one bookkeeping page before.
                 debug!("commit: realloc BK, copy {:?}", self.current_list_position);
@@ -576,7 +1366,19 @@ impl<'env> Commit for MutTxn<'env,()> {
             // Take lock
             {
                 debug!("commit: taking local lock");
-                *self.env.lock.write().unwrap();
+                let _writing = {
+                    let mut readers = try!(self.env.readers.lock());
+                    // Writers on disjoint roots commit one at a time.
+                    while readers.writing {
+                        readers = try!(self.env.readers_cond.wait(readers));
+                    }
+                    readers.writing = true;
+                    let writing = Writing(self.env);
+                    while readers.count > 0 {
+                        readers = try!(self.env.readers_cond.wait(readers));
+                    }
+                    writing
+                };
                 debug!("commit: taking file lock");
                 self.env.lock_file.lock_exclusive().unwrap();
                 debug!("commit: lock ok");
@@ -588,10 +1390,31 @@ impl<'env> Commit for MutTxn<'env,()> {
                 // last, instead of just the last one.
                 try!(self.env.mmap.flush_range(2*PAGE_SIZE, (self.env.length - 2*PAGE_SIZE_64) as usize));
 
-                *((self.env.map as *mut u64).offset(OFF_MAP_LENGTH)) = self.last_page.to_le();
+                let last_page = {
+                    let mut shared = try!(self.env.shared.lock());
+                    if self.shared_alloc {
+                        shared.last_page
+                    } else {
+                        shared.last_page = std::cmp::max(shared.last_page, self.last_page);
+                        self.last_page
+                    }
+                };
+                *((self.env.map as *mut u64).offset(OFF_MAP_LENGTH)) = last_page.to_le();
                 *((self.env.map as *mut u64).offset(OFF_CURRENT_FREE)) = current_page.offset.to_le();
+                *((self.env.map as *mut u64).offset(OFF_TXN_ID)) = (self.env.last_txn_id() + 1).to_le();
                 try!(self.env.mmap.flush_range(0, 2*PAGE_SIZE));
+                self.env.wake_txn_id();
                 self.env.lock_file.unlock().unwrap();
+                {
+                    // Commits are serialized by `env.mutable`, and
+                    // aborted writers only append to `pending_free`.
+                    let mut shared = self.env.shared.lock().unwrap_or_else(|e| e.into_inner());
+                    shared.pending_free.drain(..n_pending);
+                }
+                // The pages allocated by this transaction are now in use.
+                self.occupied_clean_pages.clear();
+                self.frozen_pages.clear();
+                self.savepoints.clear();
                 Ok(())
             }
         }
@@ -9,6 +9,8 @@ use std::ptr::copy_nonoverlapping;
 use std::io::Write;
 use std::fmt;
 use std::cmp::Ordering;
+use std::ops::Bound;
+use rand::XorShiftRng;
 #[cfg(debug_assertions)]
 use rustc_serialize::hex::ToHex;
 
@@ -20,8 +22,18 @@ pub const VALUE_SIZE_THRESHOLD: usize = (PAGE_SIZE >> 3) - BINDING_HEADER_SIZE -
 
 pub const NIL:u16 = 0xffff;
 pub const FIRST_HEAD:u16 = 0;
+pub const PAGE_HEADER_SIZE:u16 = 24;
+// On the pages of counted databases, the head is followed by the
+// number of bindings in the subtree rooted at that page.
+pub const COUNTED_PAGE_HEADER_SIZE:u16 = 32;
+// Set in the flags of the pages of counted databases. This field
+// was always 0 before counts were introduced.
+pub const COUNTED_PAGE:u16 = 1;
 pub const N_LEVELS:usize = 5;
 pub const VALUE_HEADER_LEN:usize = 8;
+// Set in the stored roots of unique databases. Pages are aligned, so
+// this bit is otherwise always 0.
+const UNIQUE_DB:u64 = 1;
 
 #[derive(Debug)]
 /// A database identifier. A `Db` can be reused in any number of transactions belonging to the same environment.
@@ -29,16 +41,31 @@ pub struct Db {
     #[doc(hidden)]
     pub root: u64,
     #[doc(hidden)]
-    pub root_num: isize
+    pub root_num: isize,
+    #[doc(hidden)]
+    pub unique: bool
 }
 
 impl Db {
     pub unsafe fn clone(&self) -> Db {
-        Db { root:self.root, root_num:self.root_num }
+        Db { root:self.root, root_num:self.root_num, unique:self.unique }
     }
     pub unsafe fn from_value(v:&[u8]) -> Db {
-        let root = u64::from_le(*(v.as_ptr() as *const u64));
-        Db { root:root, root_num: -1 }
+        Db::from_stored(-1, u64::from_le(*(v.as_ptr() as *const u64)))
+    }
+    /// Whether this database has at most one value per key (see
+    /// `MutTxn::create_unique_db`).
+    pub fn is_unique(&self) -> bool {
+        self.unique
+    }
+    // The root of this database, as stored in the file.
+    #[doc(hidden)]
+    pub fn stored(&self) -> u64 {
+        if self.unique { self.root | UNIQUE_DB } else { self.root }
+    }
+    #[doc(hidden)]
+    pub fn from_stored(root_num:isize, stored:u64) -> Db {
+        Db { root:stored & !UNIQUE_DB, root_num:root_num, unique:stored & UNIQUE_DB != 0 }
     }
 }
 
@@ -50,7 +77,18 @@ pub struct MutTxn<'env,T> {
     #[doc(hidden)]
     pub protected_pages: [u64;2],
     #[doc(hidden)]
-    pub free_protected: [bool;2]
+    pub free_protected: [bool;2],
+    #[doc(hidden)]
+    pub rng: XorShiftRng,
+    // Pages written by the current operation, whose count of bindings
+    // must be updated at the end of the operation (see `put::update_counts`).
+    #[doc(hidden)]
+    pub touched: HashSet<u64>,
+    // Bindings on the path written by the current `del` or `put`,
+    // from the bottom up, as `page | offset`, when a cursor asks for
+    // them (see `Cursor::delete_current`).
+    #[doc(hidden)]
+    pub path: Option<Vec<u64>>,
 }
 
 impl<'env,T> Drop for MutTxn<'env,T> {
@@ -69,6 +107,8 @@ pub struct Txn<'env> {
 type Error = transaction::Error;
 
 const REFERENCE_COUNTS:isize = 0;
+/// Number of root databases of an environment, see `MutTxn::set_root`.
+pub const MAX_ROOTS: usize = (transaction::ROOTS - 1) as usize;
 // pub const MAIN_ROOT:usize = 1;
 
 impl<'env,T> MutTxn<'env,T> {
@@ -76,14 +116,49 @@ impl<'env,T> MutTxn<'env,T> {
     pub fn alloc_page(&mut self) -> Result<MutPage,transaction::Error> {
         let page = try!(self.txn.alloc_page());
         // debug!("txn.alloc_page: {:?}", page.offset);
+        self.touched.insert(page.offset);
         Ok(MutPage { page: page })
     }
     #[doc(hidden)]
     pub fn load_cow_page(&mut self, off: u64) -> Cow {
-        Cow { cow: self.txn.load_cow_page(off) }
+        let cow = self.txn.load_cow_page(off);
+        if let transaction::Cow::MutPage(ref p) = cow {
+            // Pages of previous operations are written in place too,
+            // and must be counted again (see `put::update_counts`).
+            self.touched.insert(p.offset);
+        }
+        Cow { cow: cow }
+    }
+    // Start the path of the current operation at binding `off` of
+    // `page`, the one it deleted after or wrote.
+    #[doc(hidden)]
+    pub fn path_start(&mut self, page:u64, off:u16) {
+        if let Some(ref mut path) = self.path {
+            path.push(page | (off as u64))
+        }
+    }
+    // Extend the path with binding `off` of `page`, whose right child
+    // is the last page of the path. Paths that were not started at
+    // the bottom, for instance because pages were merged or split on
+    // the way, stay empty.
+    #[doc(hidden)]
+    pub fn path_push(&mut self, page:u64, off:u16) {
+        if let Some(ref mut path) = self.path {
+            if !path.is_empty() {
+                path.push(page | (off as u64))
+            }
+        }
+    }
+    #[doc(hidden)]
+    pub fn path_clear(&mut self) {
+        if let Some(ref mut path) = self.path {
+            path.clear()
+        }
     }
     #[doc(hidden)]
     pub fn set_rc(&mut self, db:Db) {
+        // Called once after each change of a single count.
+        self.txn.stats.rc_updates += 1;
         self.txn.set_root(REFERENCE_COUNTS, db.root)
     }
 
@@ -321,7 +396,7 @@ pub trait LoadPage:Sized {
         unsafe {
             let db = self.get_(page, key, None);
             if let Some(UnsafeValue::S{p,..}) = db {
-                Some(Db { root_num: -1, root: u64::from_le(*(p as *const u64)) })
+                Some(Db::from_stored(-1, u64::from_le(*(p as *const u64))))
             } else {
                 None
             }
@@ -330,6 +405,8 @@ pub trait LoadPage:Sized {
 
     fn load_page(&self, off: u64) -> Page;
 
+    fn check_page(&self, off: u64) -> Result<(), Error>;
+
     fn get_u64(&self, db: &Db, key: u64) -> Option<u64> {
         let page = self.load_page(db.root);
         self.get_u64_(page, key)
@@ -435,7 +512,7 @@ pub trait LoadPage:Sized {
                            key:&[u8],
                            value:Option<UnsafeValue>) -> Iter<'a, Self> {
 
-        let mut iter = Iter { txn:self, page_stack:[0;52], stack_pointer: 0 };
+        let mut iter = Iter::new(self, initial_page.page_offset());
         // page_stack.clear();
         iter.push(initial_page.page_offset() | (FIRST_HEAD as u64));
         
@@ -499,17 +576,207 @@ pub trait LoadPage:Sized {
         }
         iter
     }
-    
+
+    unsafe fn iter_rev_<'a>(&'a self,
+                            initial_page: &Page,
+                            key:&[u8],
+                            value:Option<UnsafeValue>) -> Iter<'a, Self> {
+        let mut iter = self.iter_(initial_page, &[], None);
+        iter.seek_back(key, value, true);
+        iter
+    }
+
+    unsafe fn iter_after_<'a>(&'a self,
+                              initial_page: &Page,
+                              token:&IterToken) -> Iter<'a, Self> {
+        let value = token.value();
+        let value = UnsafeValue::S { p:value.as_ptr(), len:value.len() as u32 };
+        let mut iter = self.iter_(initial_page, token.key(), Some(value));
+        iter.skip_binding(token.key(), value);
+        iter
+    }
+
+    unsafe fn iter_range_<'a>(&'a self,
+                              initial_page: &Page,
+                              start:Bound<&[u8]>,
+                              end:Bound<&[u8]>) -> Iter<'a, Self> {
+        let mut iter = match start {
+            Bound::Included(key) => self.iter_(initial_page, key, None),
+            Bound::Excluded(key) => {
+                let mut iter = self.iter_(initial_page, key, None);
+                iter.skip_key(key);
+                iter
+            },
+            Bound::Unbounded => self.iter_(initial_page, &[], None),
+        };
+        // The end of the range is where the back of the iterator
+        // starts, `next` stops when it gets there.
+        match end {
+            Bound::Included(key) => iter.seek_back(key, None, true),
+            Bound::Excluded(key) => iter.seek_back(key, None, false),
+            Bound::Unbounded => return iter
+        }
+        let front = iter.peek_front();
+        let back = iter.peek_back();
+        // If the range is empty, the two ends are crossed and never meet.
+        if front == 0 || back == 0 || iter.binding(front).0 > iter.binding(back).0 {
+            iter.done = true
+        }
+        iter
+    }
+
+    // Number of bindings in the subtree rooted at `page`, 0 for the
+    // empty subtree. The pages of databases created without counts
+    // are walked entirely.
+    fn count_(&self, page:u64) -> u64 {
+        if page == 0 {
+            return 0
+        }
+        let page = self.load_page(page);
+        if page.is_counted() {
+            return page.count()
+        }
+        let mut count = self.count_(page.right_child(FIRST_HEAD));
+        for (_, _, _, r) in PageIterator::new(&page, 0) {
+            count += 1 + self.count_(r)
+        }
+        count
+    }
+
+    // Number of bindings before `(key, value)`, see `is_before`. Each
+    // binding skipped in a page counts for itself and the bindings
+    // below it, and we go down at the last binding before the target.
+    unsafe fn rank_(&self, initial_page:&Page, key:&[u8], value:Option<UnsafeValue>, inclusive:bool) -> u64 {
+        let mut rank = 0;
+        let mut page_off = initial_page.page_offset();
+        loop {
+            let page = self.load_page(page_off);
+            let mut current = FIRST_HEAD;
+            loop {
+                let next = next_at_level(&page, current, 0);
+                if next == NIL || !is_before(self, &page, next, key, value, inclusive) {
+                    break
+                }
+                rank += self.count_(right_child(&page, current)) + 1;
+                current = next
+            }
+            page_off = right_child(&page, current);
+            if page_off == 0 {
+                return rank
+            }
+        }
+    }
+
+    // The binding at position `n` (starting from 0), if any.
+    unsafe fn nth_<'a>(&'a self, initial_page:&Page, mut n:u64) -> Option<(&'a [u8], UnsafeValue)> {
+        let mut page_off = initial_page.page_offset();
+        'pages: loop {
+            let page = self.load_page(page_off);
+            let mut current = FIRST_HEAD;
+            loop {
+                let child = right_child(&page, current);
+                let count = self.count_(child);
+                if n < count {
+                    page_off = child;
+                    continue 'pages
+                }
+                n -= count;
+                current = next_at_level(&page, current, 0);
+                if current == NIL {
+                    return None
+                } else if n == 0 {
+                    return Some(read_key_value(page.offset(current as isize)))
+                }
+                n -= 1
+            }
+        }
+    }
+
     fn rc(&self) -> Option<Db>;
 }
 
 pub struct Iter<'a, T:'a> {
     txn:&'a T,
+    root:u64,
     page_stack:[u64;52],
-    stack_pointer:usize
+    stack_pointer:usize,
+    // Stack of the reverse traversal, started the first time
+    // `next_back` is called (or by `iter_rev`).
+    back_stack:[u64;52],
+    back_pointer:usize,
+    back_started:bool,
+    // Next binding (page | offset) on each end, `Some(0)` if there
+    // is none, `None` if not yet computed.
+    front:Option<u64>,
+    back:Option<u64>,
+    done:bool,
+    // Last binding returned by `next`, 0 if none.
+    last:u64
 }
 
+/// Position of an iterator, from `Iter::position`, to resume
+/// iteration in a later transaction with
+/// `Transaction::iter_from_token`. It can be stored as bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IterToken(Vec<u8>);
+
+impl IterToken {
+    fn new<T:LoadPage>(key:&[u8], value:Value<T>) -> IterToken {
+        let mut token = Vec::with_capacity(2 + key.len() + value.len() as usize);
+        token.push(key.len() as u8);
+        token.push((key.len() >> 8) as u8);
+        token.extend_from_slice(key);
+        for x in value {
+            token.extend_from_slice(x)
+        }
+        IterToken(token)
+    }
+
+    /// The bytes of this token.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Read a token from bytes returned by `as_bytes`, or return
+    /// `None` if they are not a valid token.
+    pub fn from_bytes(bytes:&[u8]) -> Option<IterToken> {
+        if bytes.len() < 2 {
+            return None
+        }
+        let key_len = (bytes[0] as usize) | ((bytes[1] as usize) << 8);
+        if key_len > MAX_KEY_SIZE || 2 + key_len > bytes.len() {
+            None
+        } else {
+            Some(IterToken(bytes.to_vec()))
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        let key_len = (self.0[0] as usize) | ((self.0[1] as usize) << 8);
+        &self.0[2..2 + key_len]
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.0[2 + self.key().len()..]
+    }
+}
+
+// In the reverse stack, offset of a page we haven't started yet.
+const BACK_END:u16 = 4094;
+
 impl<'a,T:'a> Iter<'a,T> {
+    #[doc(hidden)]
+    pub fn empty(txn:&'a T) -> Self {
+        let mut iter = Iter::new(txn, 0);
+        iter.done = true;
+        iter
+    }
+    fn new(txn:&'a T, root:u64) -> Self {
+        Iter { txn:txn, root:root,
+               page_stack:[0;52], stack_pointer: 0,
+               back_stack:[0;52], back_pointer: 0, back_started: false,
+               front: None, back: None, done: false, last: 0 }
+    }
     fn push(&mut self, x:u64) {
         self.page_stack[self.stack_pointer] = x;
         self.stack_pointer += 1
@@ -518,61 +785,314 @@ impl<'a,T:'a> Iter<'a,T> {
         self.stack_pointer -= 1;
         self.page_stack[self.stack_pointer]
     }
+    fn push_back(&mut self, x:u64) {
+        self.back_stack[self.back_pointer] = x;
+        self.back_pointer += 1
+    }
 }
 
-fn offsets(x:u64) -> (u64, u16) {
+pub fn offsets(x:u64) -> (u64, u16) {
     let mask:u64 = PAGE_SIZE_64-1;
     (x & !mask, (x&mask) as u16)
 }
 
-impl<'a,'b,T:LoadPage+'a> Iterator for Iter<'a, T> {
-    type Item = (&'a[u8], Value<'a,T>);
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.stack_pointer == 0 {
+pub unsafe fn next_at_level(page:&Page, off:u16, level:usize) -> u16 {
+    u16::from_le(*(page.offset(off as isize) as *const u16).add(level))
+}
+
+pub unsafe fn right_child(page:&Page, off:u16) -> u64 {
+    u64::from_le(*(page.offset(off as isize) as *const u64).offset(2))
+}
+
+/// Last binding of a page, skipping along the highest levels first.
+pub unsafe fn last_binding(page:&Page) -> u16 {
+    let mut last = FIRST_HEAD;
+    for level in (0..N_LEVELS).rev() {
+        loop {
+            let next = next_at_level(page, last, level);
+            if next == NIL { break } else { last = next }
+        }
+    }
+    last
+}
+
+/// Binding before `off` in a page (`FIRST_HEAD` if `off` is the
+/// first one). Skip lists only point forward, so this searches the
+/// page for the last binding smaller than the one at `off`, skipping
+/// along the highest levels first.
+pub unsafe fn prev_binding<T:LoadPage>(txn:&T, page:&Page, off:u16) -> u16 {
+    let (key, value) = read_key_value(page.offset(off as isize));
+    let mut prev = FIRST_HEAD;
+    for level in (0..N_LEVELS).rev() {
+        loop {
+            let next = next_at_level(page, prev, level);
+            if next == NIL || next == off || !is_before(txn, page, next, key, Some(value), false) {
+                break
+            }
+            prev = next
+        }
+    }
+    prev
+}
+
+// Whether the binding at `off` in `page` is strictly before `(key,
+// value)`, or before or equal to it if `inclusive`. With no value,
+// all the bindings of `key` are equal to it.
+pub unsafe fn is_before<T:LoadPage>(txn:&T, page:&Page, off:u16, key:&[u8], value:Option<UnsafeValue>, inclusive:bool) -> bool {
+    let (next_key, next_value) = read_key_value(page.offset(off as isize));
+    match key.cmp(next_key) {
+        Ordering::Less => false,
+        Ordering::Equal =>
+            if let Some(value) = value {
+                match Value::from_unsafe(&value, txn).cmp(Value::from_unsafe(&next_value, txn)) {
+                    Ordering::Less => false,
+                    Ordering::Equal => inclusive,
+                    Ordering::Greater => true
+                }
+            } else {
+                inclusive
+            },
+        Ordering::Greater => true
+    }
+}
+
+/// Fill `stack` with the path from `root` to the last binding
+/// smaller than `(key, value)` (or to all bindings of `key` if
+/// `value` is `None`), or equal to it if `inclusive` is true. The
+/// last element of the path might be `FIRST_HEAD`, if there is no
+/// such binding in the page. Returns the length of the path.
+pub unsafe fn seek_path<T:LoadPage>(txn:&T, root:u64, key:&[u8], value:Option<UnsafeValue>, inclusive:bool,
+                                    stack:&mut [u64;52]) -> usize {
+    let mut pointer = 0;
+    let mut page_off = root;
+    loop {
+        let page = txn.load_page(page_off);
+        let mut current = FIRST_HEAD;
+        for level in (0..N_LEVELS).rev() {
+            loop {
+                let next = next_at_level(&page, current, level);
+                if next == NIL {
+                    break
+                }
+                if is_before(txn, &page, next, key, value, inclusive) { current = next } else { break }
+            }
+        }
+        stack[pointer] = page_off | (current as u64);
+        pointer += 1;
+        page_off = right_child(&page, current);
+        if page_off == 0 {
+            return pointer
+        }
+    }
+}
+
+impl<'a,T:LoadPage+'a> Iter<'a,T> {
+
+    // Move the front stack past the next binding, and return its
+    // address (0 if there is none).
+    unsafe fn advance_front(&mut self) -> u64 {
+        while self.stack_pointer > 0 {
+            let (page_off, current_off):(u64,u16) = offsets(self.page_stack[self.stack_pointer-1]);
+            // the binding at current_off is the next one to be sent.
+            if current_off >= 4095 {
+                self.pop();
+            } else {
+                let page = self.txn.load_page(page_off);
+                // Move the top of the stack to the next binding.
+                {
+                    let next = next_at_level(&page, current_off, 0);
+                    let next = std::cmp::min(next, 4095); // Avoid overflow.
+                    self.page_stack[self.stack_pointer-1] = page_off | (next as u64);
+                }
+                // If there's a page below, push it: the next element is there.
+                let next_page = right_child(&page, current_off);
+                if next_page != 0 {
+                    self.push(next_page | (FIRST_HEAD as u64));
+                }
+                // If we're inside the page, there's an element to
+                // return. Else, we're at the beginning of the page,
+                // the element is either in the page we just pushed,
+                // or (if there's no page below) the next element.
+                if current_off > FIRST_HEAD {
+                    return page_off | (current_off as u64)
+                }
+            }
+        }
+        0
+    }
+
+    // Move the back stack past the previous binding, and return its
+    // address (0 if there is none).
+    unsafe fn advance_back(&mut self) -> u64 {
+        while self.back_pointer > 0 {
+            let (page_off, current_off):(u64,u16) = offsets(self.back_stack[self.back_pointer-1]);
+            // the binding at current_off is the next one to be sent,
+            // after everything in the pages above it in the stack.
+            if current_off == FIRST_HEAD {
+                self.back_pointer -= 1;
+            } else {
+                let page = self.txn.load_page(page_off);
+                let last = if current_off == BACK_END {
+                    last_binding(&page)
+                } else {
+                    prev_binding(self.txn, &page, current_off)
+                };
+                // Everything in the page below `last` comes before `last`.
+                self.back_stack[self.back_pointer-1] = page_off | (last as u64);
+                let next_page = right_child(&page, last);
+                if next_page != 0 {
+                    self.push_back(next_page | (BACK_END as u64));
+                }
+                if current_off != BACK_END {
+                    return page_off | (current_off as u64)
+                }
+            }
+        }
+        0
+    }
+
+    // Start the back stack at the last binding smaller than `(key,
+    // value)`, or equal to it if `inclusive` is true.
+    unsafe fn seek_back(&mut self, key:&[u8], value:Option<UnsafeValue>, inclusive:bool) {
+        self.back_started = true;
+        self.back = None;
+        self.back_pointer = seek_path(self.txn, self.root, key, value, inclusive, &mut self.back_stack);
+    }
+
+    /// Position of the last binding returned by `next`, or `None` if
+    /// `next` hasn't returned anything yet.
+    pub fn position(&self) -> Option<IterToken> {
+        if self.last == 0 {
             None
         } else {
-            unsafe {
-                let (page_off, current_off):(u64,u16) = offsets(self.page_stack[self.stack_pointer-1]);
-                // println!("page_off = {:?} {:?}", page_off, current_off);
-                // the binding at current_off is the next one to be sent.
-                if current_off >= 4095 {
-                    // println!("pop");
-                    self.pop();
-                    self.next()
-                } else {
-                    let page = self.txn.load_page(page_off);
-                    let current:*const u16 = page.offset(current_off as isize) as *const u16;
+            let (key, value) = self.binding(self.last);
+            Some(IterToken::new(key, value))
+        }
+    }
 
-                    // We set the page stack to the next binding, and return the current one.
+    // Move the front past the binding `(key, value)`, if it is next.
+    fn skip_binding(&mut self, key:&[u8], value:UnsafeValue) {
+        let front = self.peek_front();
+        if front != 0 {
+            let (next_key, next_value) = self.binding(front);
+            if next_key == key && next_value.cmp(unsafe { Value::from_unsafe(&value, self.txn) }) == Ordering::Equal {
+                self.front = None
+            }
+        }
+    }
 
-                    // Move the top of the stack to the next binding.
-                    {
-                        let next = u16::from_le(*(current as *const u16));
-                        let next = std::cmp::min(next, 4095); // Avoid overflow.
-                        self.page_stack[self.stack_pointer-1] = page_off | (next as u64);
-                    }
-                    // If there's a page below, push it: the next element is there.
-                    let next_page = u64::from_le(*((current as *const u64).offset(2)));
-                    if next_page != 0 {
-                        // println!("push");
-                        self.push(next_page | (FIRST_HEAD as u64));
-                    }
-                    
-                    // Now, return the current element. If we're inside the page, there's an element to return.
-                    if current_off > FIRST_HEAD {
-                        let (key,value) = read_key_value(current as *const u8);
-                        Some((key, Value::from_unsafe(&value, self.txn)))
-                    } else {
-                        // Else, we're at the beginning of the page,
-                        // the element is either in the page we just
-                        // pushed, or (if there's no page below) the
-                        // next element.
-                        self.next()
-                    }
-                }
+    // Move the front past all bindings of `key`.
+    fn skip_key(&mut self, key:&[u8]) {
+        loop {
+            let front = self.peek_front();
+            if front != 0 && self.binding(front).0 == key {
+                self.front = None
+            } else {
+                break
             }
         }
     }
+
+    fn peek_front(&mut self) -> u64 {
+        if let Some(front) = self.front {
+            front
+        } else {
+            let front = unsafe { self.advance_front() };
+            self.front = Some(front);
+            front
+        }
+    }
+
+    fn peek_back(&mut self) -> u64 {
+        if !self.back_started {
+            self.back_started = true;
+            let root = self.root;
+            self.push_back(root | (BACK_END as u64))
+        }
+        if let Some(back) = self.back {
+            back
+        } else {
+            let back = unsafe { self.advance_back() };
+            self.back = Some(back);
+            back
+        }
+    }
+
+    fn binding(&self, x:u64) -> (&'a[u8], Value<'a,T>) {
+        unsafe {
+            let (page_off, off) = offsets(x);
+            let page = self.txn.load_page(page_off);
+            let (key,value) = read_key_value(page.offset(off as isize));
+            (key, Value::from_unsafe(&value, self.txn))
+        }
+    }
+}
+
+impl<'a,'b,T:LoadPage+'a> Iterator for Iter<'a, T> {
+    type Item = (&'a[u8], Value<'a,T>);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        let front = self.peek_front();
+        let back = if self.back_started { Some(self.peek_back()) } else { None };
+        if front == 0 || back == Some(0) {
+            self.done = true;
+            return None
+        }
+        if back == Some(front) {
+            // Both ends meet, this is the last binding.
+            self.done = true
+        } else {
+            self.front = None
+        }
+        self.last = front;
+        Some(self.binding(front))
+    }
+}
+
+impl<'a,T:LoadPage+'a> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        let back = self.peek_back();
+        let front = self.peek_front();
+        if front == 0 || back == 0 {
+            self.done = true;
+            return None
+        }
+        if back == front {
+            self.done = true
+        } else {
+            self.back = None
+        }
+        Some(self.binding(back))
+    }
+}
+
+/// Iterator on the values bound to a key, in increasing order,
+/// returned by `Transaction::get_all`.
+pub struct Values<'a, T:'a>(Iter<'a, T>);
+
+impl<'a,T:LoadPage+'a> Values<'a, T> {
+    #[doc(hidden)]
+    pub fn new(iter:Iter<'a, T>) -> Values<'a, T> {
+        Values(iter)
+    }
+}
+
+impl<'a,T:LoadPage+'a> Iterator for Values<'a, T> {
+    type Item = Value<'a,T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, value)| value)
+    }
+}
+
+impl<'a,T:LoadPage+'a> DoubleEndedIterator for Values<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(|(_, value)| value)
+    }
 }
 
 
@@ -599,7 +1119,7 @@ pub trait P:std::fmt::Debug {
             if first_free > 0 {
                 first_free
             } else {
-                FIRST_HEAD + 24
+                FIRST_HEAD + self.header_size()
             }
         }
     }
@@ -613,7 +1133,7 @@ pub trait P:std::fmt::Debug {
             if occupied > 0 {
                 occupied
             } else {
-                FIRST_HEAD + 24
+                FIRST_HEAD + self.header_size()
             }
         }
     }
@@ -621,6 +1141,27 @@ pub trait P:std::fmt::Debug {
         unsafe { ((self.data() as *mut u8).offset(FIRST_HEAD as isize + 12) as *mut u16) }
     }
 
+    fn p_flags(&self) -> *mut u16 {
+        unsafe { (self.data() as *mut u8).offset(FIRST_HEAD as isize + 14) as *mut u16 }
+    }
+    // Whether this page stores the number of bindings below it.
+    fn is_counted(&self) -> bool {
+        unsafe { u16::from_le(*(self.p_flags())) & COUNTED_PAGE != 0 }
+    }
+    fn header_size(&self) -> u16 {
+        if self.is_counted() { COUNTED_PAGE_HEADER_SIZE } else { PAGE_HEADER_SIZE }
+    }
+
+    // Number of bindings in the subtree rooted at this page, only
+    // valid on counted pages.
+    fn count(&self) -> u64 {
+        debug_assert!(self.is_counted());
+        unsafe { u64::from_le(*(self.p_count())) }
+    }
+    fn p_count(&self) -> *mut u64 {
+        unsafe { (self.data() as *mut u8).offset(FIRST_HEAD as isize + 24) as *mut u64 }
+    }
+
     // offset in u32.
     fn offset(&self, off: isize) -> *mut u8 {
         unsafe {
@@ -678,6 +1219,11 @@ impl P for MutPage {
 
 impl MutPage {
     pub fn init(&mut self) {
+        self.init_with(false)
+    }
+
+    // Initialize a page of a counted database if `counted` is true.
+    pub fn init_with(&mut self, counted:bool) {
         debug!("mut page init: {:?}",self);
         unsafe {
             std::ptr::write_bytes(self.page.data as *mut u8, 0, FIRST_HEAD as usize);
@@ -689,11 +1235,18 @@ impl MutPage {
             *((ptr as *mut u16).offset(4)) = NIL.to_le();
             *((ptr as *mut u16).offset(5)) = 0;
             *((ptr as *mut u16).offset(6)) = 0;
-            *((ptr as *mut u16).offset(7)) = 0;
+            *((ptr as *mut u16).offset(7)) = if counted { COUNTED_PAGE.to_le() } else { 0 };
             *((ptr as *mut u64).offset(2)) = 0; // next_page
+            if counted {
+                *(self.p_count()) = 0
+            }
         }
     }
 
+    pub fn set_count(&mut self, count:u64) {
+        unsafe { *(self.p_count()) = count.to_le() }
+    }
+
     /// Takes a size in bytes, returns an offset from the word before
     /// the beginning of the contents (0 is invalid, 1 is the first
     /// offset).
@@ -815,19 +1368,22 @@ impl<'env,T> LoadPage for MutTxn<'env,T> {
         if root == 0 {
             None
         } else {
-            Some(Db { root_num:num, root: self.txn.root(num) })
+            Some(Db::from_stored(num, root))
         }
     }
     fn load_page(&self, off: u64) -> Page {
         Page { page: self.txn.load_page(off) }
     }
+    fn check_page(&self, off: u64) -> Result<(), Error> {
+        self.txn.check_page(off)
+    }
 
     fn rc(&self) -> Option<Db> {
         let rc = self.txn.root(REFERENCE_COUNTS);
         if rc == 0 {
             None
         } else {
-            Some(Db { root_num:REFERENCE_COUNTS, root: rc })
+            Some(Db { root_num:REFERENCE_COUNTS, root: rc, unique: false })
         }
     }
 }
@@ -840,19 +1396,22 @@ impl<'env> LoadPage for Txn<'env> {
         if root == 0 {
             None
         } else {
-            Some(Db { root_num:num, root: self.txn.root(num) })
+            Some(Db::from_stored(num, root))
         }
     }
     fn load_page(&self, off: u64) -> Page {
         Page { page: self.txn.load_page(off) }
     }
+    fn check_page(&self, off: u64) -> Result<(), Error> {
+        self.txn.check_page(off)
+    }
 
     fn rc(&self) -> Option<Db> {
         let rc = self.txn.root(REFERENCE_COUNTS);
         if rc == 0 {
             None
         } else {
-            Some(Db { root_num:REFERENCE_COUNTS, root: rc })
+            Some(Db { root_num:REFERENCE_COUNTS, root: rc, unique: false })
         }
     }
 }